@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use scriptplan_core::{
+    Alias, CommandGroup, Condition, Conditional, Diagnostic, DynCommand, NodePath, Register,
+    Script, ScriptGroup, Stdin,
+};
+
+use crate::{ParseOptions, Shell};
+
+/**
+ * A script along with the settings it was written under. Which backend runs it is only decided once it's built,
+ * so the same node works with whatever backends have been registered.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandNode {
+    pub source: String,
+    pub backend: String,
+    pub shell: Shell,
+    pub stdin: Option<Stdin>,
+    pub strict: bool,
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<String>,
+}
+
+impl CommandNode {
+    pub fn to_command(
+        &self,
+        options: &ParseOptions,
+        path: &NodePath,
+    ) -> Result<DynCommand, Diagnostic> {
+        let factory = options.backends.get(&self.backend).ok_or_else(|| {
+            Diagnostic::new(
+                path.clone(),
+                format!("The backend \"{}\" does not exist", self.backend),
+            )
+        })?;
+        let command_options = ParseOptions {
+            shell: self.shell.clone(),
+            backend: self.backend.clone(),
+            stdin: self.stdin.clone(),
+            strict: self.strict,
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            ..options.clone()
+        };
+        Ok(factory(&self.source, &command_options))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ConditionNode {
+    Os(Vec<String>),
+    Arch(Vec<String>),
+    EnvSet(String),
+    EnvEquals(String, String),
+    FileExists(PathBuf),
+    Probe(CommandNode),
+    All(Vec<ConditionNode>),
+    Any(Vec<ConditionNode>),
+    Not(Box<ConditionNode>),
+}
+
+impl ConditionNode {
+    pub fn to_condition(
+        &self,
+        options: &ParseOptions,
+        path: &NodePath,
+    ) -> Result<Condition<DynCommand>, Diagnostic> {
+        let to_conditions = |nodes: &[ConditionNode]| -> Result<Vec<_>, Diagnostic> {
+            nodes
+                .iter()
+                .map(|node| node.to_condition(options, path))
+                .collect()
+        };
+        Ok(match self {
+            ConditionNode::Os(names) => Condition::Os(names.clone()),
+            ConditionNode::Arch(names) => Condition::Arch(names.clone()),
+            ConditionNode::EnvSet(name) => Condition::EnvSet(name.clone()),
+            ConditionNode::EnvEquals(name, value) => {
+                Condition::EnvEquals(name.clone(), value.clone())
+            }
+            ConditionNode::FileExists(file) => Condition::FileExists(file.clone()),
+            ConditionNode::Probe(command) => Condition::Probe(command.to_command(options, path)?),
+            ConditionNode::All(nodes) => Condition::All(to_conditions(nodes)?),
+            ConditionNode::Any(nodes) => Condition::Any(to_conditions(nodes)?),
+            ConditionNode::Not(node) => Condition::Not(Box::new(node.to_condition(options, path)?)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupKind {
+    Series,
+    Parallel,
+    Pipe,
+}
+
+impl GroupKind {
+    /**
+     * The key the group's members are listed under
+     */
+    pub fn key(&self) -> &'static str {
+        match self {
+            GroupKind::Series => "series",
+            GroupKind::Parallel => "parallel",
+            GroupKind::Pipe => "pipe",
+        }
+    }
+}
+
+/**
+ * An owned form of a task that doesn't borrow from the file it was loaded from, which lets it be cached
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScriptNode {
+    Command(CommandNode),
+    Alias {
+        task: String,
+        args: Vec<String>,
+    },
+    /// Always has at least one member
+    Group {
+        kind: GroupKind,
+        members: Vec<ScriptNode>,
+    },
+    Conditional {
+        condition: ConditionNode,
+        script: Box<ScriptNode>,
+    },
+    Register {
+        stdout: Option<String>,
+        status: Option<String>,
+        script: Box<ScriptNode>,
+    },
+}
+
+impl ScriptNode {
+    /**
+     * Builds the script that actually gets run. Anything that goes wrong is reported against the given path, which is
+     * the node's own path as far as groups and aliases go since those are always written under their own key.
+     */
+    pub fn to_script(
+        &self,
+        options: &ParseOptions,
+        path: &NodePath,
+    ) -> Result<Script<DynCommand>, Diagnostic> {
+        Ok(match self {
+            ScriptNode::Command(command) => Script::Command(command.to_command(options, path)?),
+            ScriptNode::Alias { task, args } => Script::Alias(Alias {
+                task: task.clone(),
+                args: args.iter().cloned().map(Arc::new).collect(),
+                path: path.key("task"),
+            }),
+            ScriptNode::Group { kind, members } => {
+                let path = path.key(kind.key());
+                let mut scripts = members
+                    .iter()
+                    .enumerate()
+                    .map(|(i, member)| member.to_script(options, &path.index(i)).map(Arc::new));
+                let first = scripts.next().ok_or_else(|| {
+                    Diagnostic::new(path.clone(), "A group must contain at least one script")
+                })??;
+                let group = ScriptGroup {
+                    bail: false,
+                    first,
+                    rest: scripts.collect::<Result<_, _>>()?,
+                };
+                Script::Group(Box::new(match kind {
+                    GroupKind::Series => CommandGroup::Series(group),
+                    GroupKind::Parallel => CommandGroup::Parallel(group),
+                    GroupKind::Pipe => CommandGroup::Pipe(group),
+                }))
+            }
+            ScriptNode::Conditional { condition, script } => {
+                Script::Conditional(Box::new(Conditional {
+                    condition: condition.to_condition(options, path)?,
+                    script: script.to_script(options, path)?,
+                }))
+            }
+            ScriptNode::Register {
+                stdout,
+                status,
+                script,
+            } => Script::Register(Box::new(Register {
+                stdout: stdout.clone(),
+                status: status.clone(),
+                script: script.to_script(options, path)?,
+            })),
+        })
+    }
+}
+
+/**
+ * What the command line needs to know about a task without building it
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskInfo {
+    pub description: Option<String>,
+    pub hidden: bool,
+    pub aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskNode {
+    pub info: TaskInfo,
+    pub script: ScriptNode,
+}
+
+/**
+ * Everything in a script file, with every task already turned into a node
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub shell: Shell,
+    pub backend: String,
+    pub strict: bool,
+    /// As written in the file. They get checked and turned into commands by ScriptFileParser::vars
+    pub vars: BTreeMap<String, String>,
+    pub default: Option<String>,
+    pub tasks: BTreeMap<String, TaskNode>,
+}
@@ -0,0 +1,149 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use scriptplan_core::Platform;
+
+use crate::{PlanFile, Source};
+
+#[derive(Serialize, Deserialize)]
+struct CachedPlan {
+    key: String,
+    plan: PlanFile,
+}
+
+fn hex_digest(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Lengths go in too so that moving bytes between parts changes the digest
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/**
+ * Parsed plans, stored in a binary format so that large script files don't need parsing on every run.
+ * Each script file gets one entry, which is only used while the file's contents (and the platform and scriptplan
+ * version it was parsed for) stay the same.
+ */
+pub struct PlanCache {
+    pub dir: PathBuf,
+}
+
+impl PlanCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        PlanCache { dir: dir.into() }
+    }
+
+    /**
+     * $XDG_CACHE_HOME/scriptplan, falling back to ~/.cache/scriptplan and then the temp directory
+     */
+    pub fn default_dir() -> PathBuf {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir)
+            .join("scriptplan")
+    }
+
+    fn entry(&self, source: &Source) -> PathBuf {
+        // Sources read from disk are named after their path, which might be relative
+        let path = fs::canonicalize(&source.name).unwrap_or_else(|_| PathBuf::from(&source.name));
+        let name = hex_digest(&[path.as_os_str().as_encoded_bytes()]);
+        self.dir.join(format!("{}.bin", name))
+    }
+
+    fn key(source: &Source, platform: Option<Platform>) -> String {
+        let platform = platform.map_or("", |platform| platform.as_str());
+        hex_digest(&[
+            env!("CARGO_PKG_VERSION").as_bytes(),
+            platform.as_bytes(),
+            format!("{:?}", source.format).as_bytes(),
+            source.contents.as_bytes(),
+        ])
+    }
+
+    /**
+     * Anything that can't be read, including entries written by other versions, counts as a miss
+     */
+    pub fn load(&self, source: &Source, platform: Option<Platform>) -> Option<PlanFile> {
+        let bytes = fs::read(self.entry(source)).ok()?;
+        let cached: CachedPlan = bincode::deserialize(&bytes).ok()?;
+        if cached.key == PlanCache::key(source, platform) {
+            Some(cached.plan)
+        } else {
+            None
+        }
+    }
+
+    pub fn store(
+        &self,
+        source: &Source,
+        platform: Option<Platform>,
+        plan: PlanFile,
+    ) -> io::Result<()> {
+        let cached = CachedPlan {
+            key: PlanCache::key(source, platform),
+            plan,
+        };
+        let bytes = bincode::serialize(&cached)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::create_dir_all(&self.dir)?;
+        let entry = self.entry(source);
+        // Renaming means that runs happening at the same time never see a half written entry
+        let partial = entry.with_extension(format!("{}.partial", std::process::id()));
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, ScriptFileParser};
+    use std::convert::TryFrom;
+
+    /**
+     * A cache in a directory of its own, removed even if an assert fails
+     */
+    struct TempCache(PlanCache);
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    #[test]
+    fn only_uses_plans_for_the_same_contents() {
+        let temp = TempCache(PlanCache::new(
+            std::env::temp_dir().join(format!("scriptplan-cache-test-{}", std::process::id())),
+        ));
+        let cache = &temp.0;
+        let source = Source::new(
+            "scripts.yaml",
+            "vars:\n  at: $(date)\nbuild:\n  aliases: b\n  series:\n    - cargo build\n    - task: test --all\n      if:\n        os: linux\ntest:\n  stdin: null\n  script: cargo test\n",
+            Format::Yaml,
+        );
+        let parser = ScriptFileParser::try_from(&source.parse().unwrap()).unwrap();
+        let plan = parser.compile().unwrap();
+
+        assert_eq!(cache.load(&source, Some(Platform::Linux)), None);
+        cache
+            .store(&source, Some(Platform::Linux), plan.clone())
+            .unwrap();
+        assert_eq!(cache.load(&source, Some(Platform::Linux)), Some(plan));
+        assert_eq!(cache.load(&source, Some(Platform::MacOS)), None);
+
+        let edited = Source::new("scripts.yaml", "build: make\n", Format::Yaml);
+        assert_eq!(cache.load(&edited, Some(Platform::Linux)), None);
+    }
+}
@@ -0,0 +1,292 @@
+use scriptplan_core::{did_you_mean, validate, Diagnostic, NodePath};
+
+use crate::{
+    reserved_key_used_as_task, ScriptFile, ScriptFileParser, TaskDefinition, TaskSummary, TaskTable,
+};
+
+const TASK_KEYS: [&str; 16] = [
+    "description",
+    "hidden",
+    "aliases",
+    "if",
+    "shell",
+    "stdin",
+    "env",
+    "cwd",
+    "strict",
+    "backend",
+    "register",
+    "task",
+    "script",
+    "series",
+    "parallel",
+    "pipe",
+];
+
+fn lint_definition(definition: &TaskDefinition, path: &NodePath, out: &mut Vec<Diagnostic>) {
+    let table = match definition {
+        TaskDefinition::Command(_) => return,
+        TaskDefinition::Table(table) => table,
+    };
+
+    for key in table.unknown.keys() {
+        let mut message = format!("\"{}\" isn't a known key", key);
+        if let Some(suggestion) = did_you_mean(key, TASK_KEYS) {
+            message += &format!(". Did you mean \"{}\"?", suggestion);
+        }
+        out.push(Diagnostic::new(path.key(key), message).about_key());
+    }
+
+    lint_kinds(table, path, out);
+
+    let groups = [
+        ("series", &table.series),
+        ("parallel", &table.parallel),
+        ("pipe", &table.pipe),
+    ];
+    for (key, group) in groups {
+        for (i, member) in group.iter().flatten().enumerate() {
+            lint_definition(member, &path.key(key).index(i), out);
+        }
+    }
+}
+
+/**
+ * Only the first of task, script, series, parallel or pipe gets used so having more than one is almost certainly a mistake
+ */
+fn lint_kinds(table: &TaskTable, path: &NodePath, out: &mut Vec<Diagnostic>) {
+    let present: Vec<&str> = [
+        ("task", table.task.is_some()),
+        ("script", table.script.is_some()),
+        ("series", table.series.is_some()),
+        ("parallel", table.parallel.is_some()),
+        ("pipe", table.pipe.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, is_present)| *is_present)
+    .map(|(key, _)| key)
+    .collect();
+
+    if present.len() > 1 {
+        out.push(
+            Diagnostic::new(
+                path.key(present[1]),
+                format!(
+                    "Only one of task, script, series, parallel or pipe can be used but found {}",
+                    present.join(", ")
+                ),
+            )
+            .about_key(),
+        );
+    }
+}
+
+/**
+ * Aliases share the command line with task names so they can't overlap with any
+ */
+fn lint_aliases(script_file: &ScriptFile, out: &mut Vec<Diagnostic>) {
+    let mut seen: Vec<(String, &str)> = Vec::new();
+    for (name, definition) in script_file.tasks.iter() {
+        let path = NodePath::task(name).key("aliases");
+        for alias in TaskSummary::of(definition).aliases() {
+            if script_file.tasks.contains_key(alias) {
+                out.push(Diagnostic::new(
+                    path.clone(),
+                    format!("The alias \"{}\" is already the name of a task", alias),
+                ));
+            } else if let Some((_, other)) = seen.iter().find(|(seen, _)| seen == alias) {
+                out.push(Diagnostic::new(
+                    path.clone(),
+                    format!("The alias \"{}\" is already used by {}", alias, other),
+                ));
+            } else {
+                seen.push((alias.to_string(), name));
+            }
+        }
+    }
+}
+
+/**
+ * Problems with the shape of a script file that parsing alone doesn't catch
+ */
+pub fn lint(script_file: &ScriptFile) -> Vec<Diagnostic> {
+    let mut out = Vec::new();
+    for (name, value) in script_file.tasks.iter() {
+        let path = NodePath::task(name);
+        match TaskDefinition::from_value(value, &path) {
+            Ok(definition) => lint_definition(&definition, &path, &mut out),
+            Err(diagnostic) => out.push(diagnostic),
+        }
+    }
+    lint_aliases(script_file, &mut out);
+    // The other settings can't hold a table so a task with their name doesn't get this far
+    out.extend(reserved_key_used_as_task(
+        "vars",
+        script_file.vars.keys().map(String::as_str),
+    ));
+    out
+}
+
+/**
+ * Everything `scriptplan check` reports, in the order they appear in the file where possible
+ */
+pub fn check(script_file: &ScriptFile, parser: &ScriptFileParser) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let default = parser.default_task().and_then(Result::err);
+    let vars = parser.vars().err();
+    for diagnostic in lint(script_file)
+        .into_iter()
+        .chain(default)
+        .chain(vars)
+        .chain(validate(parser))
+    {
+        let diagnostic = parser.locate(diagnostic);
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Format;
+    use scriptplan_core::ScriptParser;
+    use std::convert::TryFrom;
+
+    fn check_str(yaml_str: &str) -> Vec<String> {
+        let script_file = ScriptFile::parse_str(yaml_str, Format::Yaml).unwrap();
+        let parser = ScriptFileParser::try_from(&script_file).unwrap();
+        check(&script_file, &parser)
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_broken_task() {
+        let yaml_str = "bad: 5\nok: echo ok\nworse:\n  series:\n    - [cargo, test]\n";
+        assert_eq!(
+            check_str(yaml_str),
+            vec![
+                "bad: Expected a command or a table with one of task, script, series, parallel or pipe but found 5",
+                "worse: Expected a command or a table with one of task, script, series, parallel or pipe but found [\"cargo\",\"test\"]",
+            ]
+        );
+        // The rest of the file can still be run
+        let script_file = ScriptFile::parse_str(yaml_str, Format::Yaml).unwrap();
+        let parser = ScriptFileParser::try_from(&script_file).unwrap();
+        assert!(parser.parse("ok").is_ok());
+        assert_eq!(
+            parser.parse("bad").unwrap_err().message,
+            "Expected a command or a table with one of task, script, series, parallel or pipe but found 5"
+        );
+    }
+
+    #[test]
+    fn reports_problems_across_the_file() {
+        let diagnostics = check_str(
+            r#"
+build:
+  series:
+    - task: compile
+    - task: missing
+  descripton: Typo
+compile:
+  script: gcc
+  task: build
+empty:
+  parallel: []
+deploy: scp $1 $2
+release:
+  series:
+    - task: deploy
+"#,
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "build: descripton: \"descripton\" isn't a known key. Did you mean \"description\"?",
+                "compile: script: Only one of task, script, series, parallel or pipe can be used but found task, script",
+                "build: series[1].task: The task \"missing\" does not exist",
+                "empty: parallel: A group must contain at least one script",
+                "deploy: References argument 2 but only 0 can ever be passed to it when run through release -> deploy",
+                "compile: task: Alias cycle: build -> compile -> build",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_cycles_without_retracing_shared_tasks() {
+        // Every task in a layer aliases both tasks in the next one, so there are 2^40 ways down to the bottom
+        let layers = 40;
+        let mut yaml_str = String::new();
+        for layer in 0..layers {
+            for side in ["a", "b"] {
+                yaml_str += &format!(
+                    "l{0}{1}:\n  series:\n    - task: l{2}a\n    - task: l{2}b\n",
+                    layer,
+                    side,
+                    layer + 1
+                );
+            }
+        }
+        yaml_str += &format!("l{}a:\n  task: l0a\nl{}b: echo\n", layers, layers);
+
+        let diagnostics = check_str(&yaml_str);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("l40a: task: Alias cycle: l0a -> l1a -> l2a"));
+    }
+
+    #[test]
+    fn reports_clashing_aliases() {
+        let diagnostics = check_str(
+            r#"
+build:
+  aliases: [b, test]
+  script: cargo build
+test:
+  aliases: b
+  script: cargo test
+"#,
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "build: aliases: The alias \"test\" is already the name of a task",
+                "test: aliases: The alias \"b\" is already used by build",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unusable_defaults() {
+        assert_eq!(
+            check_str("default: biuld\nbuild: cargo build\n"),
+            vec!["default: The task \"biuld\" does not exist. Did you mean \"build\"?"]
+        );
+        assert_eq!(
+            check_str("default: _build\n_build: cargo build\n"),
+            vec!["default: The default task \"_build\" is hidden"]
+        );
+    }
+
+    #[test]
+    fn reports_vars_that_were_meant_to_be_a_task() {
+        assert_eq!(
+            check_str("vars:\n  series: echo\nbuild: cargo build\n"),
+            vec![
+                "vars: \"vars\" is a setting for the whole file so it can't be the name of a task"
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_valid_files() {
+        assert!(check_str("build: cargo build\ntest:\n  task: build --release\n").is_empty());
+        assert!(check_str("default: b\nbuild:\n  aliases: b\n  script: cargo build\n").is_empty());
+    }
+}
@@ -1,15 +1,11 @@
-use yaml_rust::yaml::Hash;
-use yaml_rust::Yaml;
-
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap};
 
 use std::convert::TryFrom;
+use std::fmt;
 use std::ops::Deref;
+use std::path::Path;
 use std::process::Stdio;
-use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use shellwords::split;
 
@@ -18,20 +14,43 @@ use async_trait::async_trait;
 use std::process::ExitStatus;
 
 use scriptplan_core::Command;
-use scriptplan_core::ScriptGroup;
+use scriptplan_core::DynCommand;
 use scriptplan_core::ScriptParser;
 use scriptplan_core::VarArgs;
-use scriptplan_core::{Alias, CommandGroup, Script};
+use scriptplan_core::{Diagnostic, LazyVar, NodePath, Platform, RunContext, Script, Stdin};
 
 use tokio;
 use tokio::io::AsyncWriteExt;
 
 pub extern crate scriptplan_core;
-pub extern crate yaml_rust;
+
+mod ast;
+mod cache;
+mod check;
+mod schema;
+mod shell;
+mod source;
+pub use ast::*;
+pub use cache::*;
+pub use check::*;
+pub use schema::*;
+pub use shell::*;
+pub use source::*;
 
 #[derive(Debug)]
 pub struct BashCommand {
     pub command_str: String,
+    pub shell: Shell,
+    /// Falls back to the context's default when not set
+    pub stdin: Option<Stdin>,
+    /// Stops shell scripts at the first command that fails
+    pub strict: bool,
+    /// Prints each command as it's run, prefixed with the task it's part of
+    pub trace: bool,
+    /// Set on top of the vars the script uses. Values can use ${var}.
+    pub env: BTreeMap<String, String>,
+    /// Runs in the current directory when not set. Can use ${var}.
+    pub cwd: Option<String>,
 }
 
 impl From<&str> for BashCommand {
@@ -42,166 +61,1061 @@ impl From<&str> for BashCommand {
 
 impl From<String> for BashCommand {
     fn from(command_str: String) -> Self {
-        BashCommand { command_str }
+        BashCommand {
+            command_str,
+            shell: Shell::default(),
+            stdin: None,
+            strict: true,
+            trace: false,
+            env: BTreeMap::new(),
+            cwd: None,
+        }
+    }
+}
+
+impl fmt::Display for BashCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.shell != Shell::default() {
+            write!(f, "({}) ", self.shell)?;
+        }
+        write!(f, "{}", self.command_str)
     }
 }
 
 #[async_trait]
 impl Command for BashCommand {
-    async fn run(&self, vars: VarArgs) -> Result<ExitStatus, ()> {
-        let args: VecDeque<&str> = vars.iter().map(|x| (*x).as_str()).collect();
-        let mut process = tokio::process::Command::new("bash")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::inherit())
+    async fn run(&self, context: &RunContext, vars: VarArgs) -> Result<ExitStatus, ()> {
+        let args: Vec<&str> = vars.iter().map(|x| (*x).as_str()).collect();
+        let task = context.task();
+        let trace = self.trace.then_some(task.as_str());
+        // Every interpreter can read vars from the environment, which is safer than pasting them into the script
+        let vars = context.vars_in(&self.command_str).await?;
+        let env = substitute_env(&self.env, context).await?;
+        let cwd = match &self.cwd {
+            Some(cwd) => Some(context.substitute_vars(cwd).await?),
+            None => None,
+        };
+        let script = self.shell.prelude(self.strict, trace) + &self.command_str;
+        let invocation = self.shell.invocation(&script, &args);
+        let stdin = self
+            .stdin
+            .clone()
+            .unwrap_or_else(|| context.default_stdin());
+
+        let temp_script = match &invocation.script {
+            ScriptDelivery::File(body) => Some(TempScript::write(body).map_err(|err| {
+                eprintln!("Unable to write the script to a temporary file: {}", err);
+            })?),
+            _ => None,
+        };
+        let stdin_stdio = match &invocation.script {
+            ScriptDelivery::Stdin(_) => Stdio::piped(),
+            _ => context.stdin_stdio(&stdin).map_err(|err| {
+                eprintln!("Unable to open \"{}\" for stdin: {}", stdin, err);
+            })?,
+        };
+
+        let stdout_stdio = context.stdout_stdio().map_err(|err| {
+            eprintln!("Unable to connect stdout to the pipe: {}", err);
+        })?;
+
+        let _terminal = context.lock_terminal(&stdin).await;
+        let mut process = tokio::process::Command::new(&invocation.program);
+        if let Some(cwd) = &cwd {
+            process.current_dir(cwd);
+        }
+        let mut process = process
+            .stdin(stdin_stdio)
+            .stdout(stdout_stdio)
+            .stderr(context.output_stdio())
             // The following remove prompt strings from bash
             .env("PS0", "")
             .env("PS1", "")
             .env("PS2", "")
-            // Stops input from being read by output
-            .arg("-s")
-            .arg("--")
-            .args(args)
+            .envs(vars)
+            .envs(env)
+            .args(temp_script.iter().map(|file| file.path.as_os_str()))
+            .args(invocation.args)
             .spawn()
-            .unwrap();
-
-        /*
-           By default, we want it to be easy for users to be able to apply arguments to the subprocesses that scriptplan executes.
-           However, if a user explicitly says, say, they want to use arguments in the following order: "$1 $2" then it's probably not a good idea to spread all arguments.
-        */
-        let has_params = self.command_str.contains("$");
-        let spread_args = if has_params { "" } else { " $@" };
+            .map_err(|err| match &cwd {
+                Some(cwd) => eprintln!(
+                    "Unable to start \"{}\" in \"{}\": {}",
+                    invocation.program, cwd, err
+                ),
+                None => eprintln!("Unable to start \"{}\": {}", invocation.program, err),
+            })?;
 
-        process
-            .stdin
-            .take()
-            .unwrap()
-            .write_all((self.command_str.to_string() + spread_args).as_bytes())
-            .await
-            .unwrap();
+        if let ScriptDelivery::Stdin(body) = invocation.script {
+            let mut stdin = process.stdin.take().unwrap();
+            stdin.write_all(body.as_bytes()).await.map_err(|err| {
+                eprintln!(
+                    "Unable to send the script to \"{}\": {}",
+                    invocation.program, err
+                );
+            })?;
+            // Closing stdin lets the interpreter know the script has ended
+            drop(stdin);
+        }
 
-        let output = process.wait_with_output().await.unwrap();
+        context.wait_for(process).await.map_err(|err| {
+            eprintln!("Unable to wait for \"{}\": {}", invocation.program, err);
+        })
+    }
 
-        let status = output.status;
+    fn required_args(&self) -> Option<usize> {
+        match self.shell {
+            Shell::Sh | Shell::Bash | Shell::Zsh => Some(highest_positional(&self.command_str)),
+            _ => None,
+        }
+    }
+}
 
-        return Ok(status);
+/**
+ * Works out the values of a task's env key
+ */
+async fn substitute_env(
+    env: &BTreeMap<String, String>,
+    context: &RunContext,
+) -> Result<BTreeMap<String, String>, ()> {
+    let mut values = BTreeMap::new();
+    for (name, value) in env {
+        values.insert(name.clone(), context.substitute_vars(value).await?);
     }
+    Ok(values)
 }
 
-fn parse_command(command_str: &str) -> Script<BashCommand> {
-    Script::Command(command_str.into())
+/**
+ * The highest positional parameter (E.g. $2 or ${10}) a shell script references. $0 is the shell itself so it doesn't count.
+ */
+fn highest_positional(script: &str) -> usize {
+    let mut highest = 0;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '$' => {
+                let braced = chars.next_if_eq(&'{').is_some();
+                let mut digits = String::new();
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    digits.push(digit);
+                    // Without braces only a single digit is part of the parameter
+                    if !braced {
+                        break;
+                    }
+                }
+                if braced && chars.peek() != Some(&'}') {
+                    continue;
+                }
+                if let Ok(index) = digits.parse::<usize>() {
+                    highest = highest.max(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    highest
 }
 
-fn parse_alias(alias_str: &str) -> Script<BashCommand> {
-    let mut words: Vec<_> = split(alias_str).unwrap();
-    Script::Alias(Alias {
-        task: words.remove(0),
-        args: words.into_iter().map(|string| Arc::new(string)).collect(),
+/**
+ * Turns the source of a script into a command for a particular backend
+ */
+pub type CommandFactory = fn(&str, &ParseOptions) -> DynCommand;
+
+fn new_bash_command(command_str: &str, options: &ParseOptions) -> DynCommand {
+    Box::new(BashCommand {
+        command_str: command_str.to_string(),
+        shell: options.shell.clone(),
+        stdin: options.stdin.clone(),
+        strict: options.strict,
+        trace: options.trace,
+        env: options.env.clone(),
+        cwd: options.cwd.clone(),
     })
 }
 
-fn yaml_to_group(yaml: &Yaml) -> Result<ScriptGroup<BashCommand>, ()> {
-    let yaml_list = yaml.as_vec().ok_or(())?;
+pub const DEFAULT_BACKEND: &str = "bash";
 
-    let mut scripts_iter = yaml_list.iter().map(yaml_to_script);
+/**
+ * Settings that affect how tasks get parsed, as opposed to how they're run
+ */
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Which variant to pick when a script has platform specific variants
+    pub platform: Option<Platform>,
+    /// Can be overridden per task (and its children) with the "shell" key
+    pub shell: Shell,
+    /// Can be overridden per task (and its children) with the "backend" key
+    pub backend: String,
+    /// Can be overridden per task (and its children) with the "stdin" key. None leaves it up to the context.
+    pub stdin: Option<Stdin>,
+    /// Can be overridden per task (and its children) with the "strict" key
+    pub strict: bool,
+    /// Added to with the "env" key, for the task and its children
+    pub env: BTreeMap<String, String>,
+    /// Can be overridden per task (and its children) with the "cwd" key. Relative to the parent task's cwd.
+    pub cwd: Option<String>,
+    /// Turned on for every task at once rather than being part of the file
+    pub trace: bool,
+    pub backends: HashMap<String, CommandFactory>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            platform: Platform::current(),
+            shell: Shell::default(),
+            backend: DEFAULT_BACKEND.to_string(),
+            stdin: None,
+            strict: true,
+            env: BTreeMap::new(),
+            cwd: None,
+            trace: false,
+            backends: HashMap::from([(
+                DEFAULT_BACKEND.to_string(),
+                new_bash_command as CommandFactory,
+            )]),
+        }
+    }
+}
 
-    let first = (scripts_iter.next().unwrap())?;
+/**
+ * The backend has to exist by the time a task gets parsed, even though the command itself is only created when the
+ * task gets built
+ */
+fn new_command(
+    command_str: &str,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<CommandNode, Diagnostic> {
+    if !options.backends.contains_key(&options.backend) {
+        return Err(Diagnostic::new(
+            path.clone(),
+            format!("The backend \"{}\" does not exist", options.backend),
+        ));
+    }
+    Ok(CommandNode {
+        source: command_str.to_string(),
+        backend: options.backend.clone(),
+        shell: options.shell.clone(),
+        stdin: options.stdin.clone(),
+        strict: options.strict,
+        env: options.env.clone(),
+        cwd: options.cwd.clone(),
+    })
+}
 
-    let scripts_result: Result<VecDeque<_>, _> = scripts_iter.collect();
-    let scripts = scripts_result?;
+fn parse_command(
+    command_str: &str,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    Ok(ScriptNode::Command(new_command(
+        command_str,
+        options,
+        path,
+    )?))
+}
 
-    Ok(ScriptGroup {
-        bail: false,
-        first,
-        rest: scripts,
+fn parse_alias(alias_str: &str, path: &NodePath) -> Result<ScriptNode, Diagnostic> {
+    let mut words: Vec<_> = split(alias_str)
+        .map_err(|_| Diagnostic::new(path.clone(), "The task has mismatched quotes"))?;
+    if words.is_empty() {
+        return Err(Diagnostic::new(path.clone(), "The task name is empty"));
+    }
+    Ok(ScriptNode::Alias {
+        task: words.remove(0),
+        args: words,
     })
 }
 
-fn yaml_to_script(yaml: &Yaml) -> Result<Script<BashCommand>, ()> {
-    if let Some(command_str) = yaml.as_str() {
-        return Ok(parse_command(command_str));
-    } else if let Some(hash) = yaml.as_hash() {
-        if let Some(task) = hash.get(&Yaml::from_str("task")) {
-            // TODO: Need a splitn
-            return Ok(parse_alias(task.as_str().unwrap()));
-        } else if let Some(command_str) = hash.get(&Yaml::from_str("script")) {
-            return Ok(parse_command(command_str.as_str().unwrap()));
-        } else if let Some(serial_yaml) = hash.get(&Yaml::from_str("series")) {
-            Ok(Script::Group(Box::new(CommandGroup::Series(
-                yaml_to_group(serial_yaml)?,
-            ))))
-        } else if let Some(parallel_yaml) = hash.get(&Yaml::from_str("parallel")) {
-            Ok(Script::Group(Box::new(CommandGroup::Parallel(
-                yaml_to_group(parallel_yaml)?,
-            ))))
-        } else {
-            panic!("should never happen");
+fn definitions_to_group(
+    kind: GroupKind,
+    definitions: &[TaskDefinition],
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    if definitions.is_empty() {
+        return Err(Diagnostic::new(
+            path.clone(),
+            "A group must contain at least one script",
+        ));
+    }
+    let members = definitions
+        .iter()
+        .enumerate()
+        .map(|(i, definition)| definition_to_node(definition, options, &path.index(i)))
+        .collect::<Result<_, _>>()?;
+    Ok(ScriptNode::Group { kind, members })
+}
+
+fn env_to_condition(env: &EnvDefinition) -> ConditionNode {
+    match env {
+        EnvDefinition::Names(names) => ConditionNode::All(
+            names
+                .to_vec()
+                .into_iter()
+                .map(ConditionNode::EnvSet)
+                .collect(),
+        ),
+        EnvDefinition::Values(values) => ConditionNode::All(
+            values
+                .iter()
+                .map(|(name, value)| ConditionNode::EnvEquals(name.clone(), value.to_string()))
+                .collect(),
+        ),
+    }
+}
+
+/**
+ * A string is shorthand for a probe command. Otherwise every key in the table has to hold.
+ */
+fn definition_to_condition(
+    definition: &ConditionDefinition,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ConditionNode, Diagnostic> {
+    let table = match definition {
+        ConditionDefinition::Probe(command_str) => {
+            return Ok(ConditionNode::Probe(new_command(
+                command_str,
+                options,
+                path,
+            )?));
         }
+        ConditionDefinition::Table(table) => table,
+    };
+
+    if let Some(key) = table.unknown.keys().next() {
+        return Err(
+            Diagnostic::new(path.key(key), format!("\"{}\" isn't a condition", key)).about_key(),
+        );
+    }
+
+    let to_conditions =
+        |definitions: &Vec<ConditionDefinition>, path: NodePath| -> Result<Vec<_>, Diagnostic> {
+            definitions
+                .iter()
+                .enumerate()
+                .map(|(i, definition)| definition_to_condition(definition, options, &path.index(i)))
+                .collect()
+        };
+
+    let mut conditions = Vec::new();
+    if let Some(os) = &table.os {
+        conditions.push(ConditionNode::Os(os.to_vec()));
+    }
+    if let Some(arch) = &table.arch {
+        conditions.push(ConditionNode::Arch(arch.to_vec()));
+    }
+    if let Some(env) = &table.env {
+        conditions.push(env_to_condition(env));
+    }
+    if let Some(file) = &table.file {
+        conditions.push(ConditionNode::All(
+            file.to_vec()
+                .into_iter()
+                .map(|path| ConditionNode::FileExists(path.into()))
+                .collect(),
+        ));
+    }
+    if let Some(command_str) = &table.command {
+        conditions.push(ConditionNode::Probe(new_command(
+            command_str,
+            options,
+            &path.key("command"),
+        )?));
+    }
+    if let Some(not) = &table.not {
+        conditions.push(ConditionNode::Not(Box::new(definition_to_condition(
+            not,
+            options,
+            &path.key("not"),
+        )?)));
+    }
+    if let Some(any) = &table.any {
+        conditions.push(ConditionNode::Any(to_conditions(any, path.key("any"))?));
+    }
+    if let Some(all) = &table.all {
+        conditions.push(ConditionNode::All(to_conditions(all, path.key("all"))?));
+    }
+
+    if conditions.len() == 1 {
+        Ok(conditions.remove(0))
     } else {
-        panic!("should never happen");
+        Ok(ConditionNode::All(conditions))
     }
 }
 
-enum YamlOrTask<'a> {
-    NotLoaded(&'a Yaml),
-    Loaded(Rc<Script<BashCommand>>),
+/**
+ * Scripts are either a plain string or a map of platform names (plus "default") to strings
+ */
+fn source_to_command_str<'s>(
+    source: &'s ScriptSource,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<&'s str, Diagnostic> {
+    let variants = match source {
+        ScriptSource::Plain(command_str) => return Ok(command_str),
+        ScriptSource::PerPlatform(variants) => variants,
+    };
+
+    for key in variants.keys() {
+        if key != "default" && key.parse::<Platform>().is_err() {
+            return Err(
+                Diagnostic::new(path.key(key), format!("\"{}\" isn't a platform", key)).about_key(),
+            );
+        }
+    }
+
+    options
+        .platform
+        .and_then(|platform| variants.get(platform.as_str()))
+        .or_else(|| variants.get("default"))
+        .map(String::as_str)
+        .ok_or_else(|| {
+            Diagnostic::new(
+                path.clone(),
+                match options.platform {
+                    Some(platform) => format!("There's no variant for {} or a default", platform),
+                    None => "There's no default variant for this platform".to_string(),
+                },
+            )
+        })
 }
 
-pub struct LazyTask<'a> {
-    yaml_or_task: RefCell<YamlOrTask<'a>>,
+fn definition_to_stdin(
+    definition: &StdinDefinition,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<Stdin, Diagnostic> {
+    let path = path.key("stdin");
+    if let Shell::Custom(_) = options.shell {
+        return Err(Diagnostic::new(
+            path,
+            format!(
+                "Scripts run with \"{}\" are read from stdin so it can't be changed",
+                options.shell
+            ),
+        ));
+    }
+    match definition {
+        StdinDefinition::Mode(mode) if mode == "inherit" => Ok(Stdin::Inherit),
+        StdinDefinition::Mode(mode) if mode == "null" => Ok(Stdin::Null),
+        StdinDefinition::Mode(mode) => Err(Diagnostic::new(
+            path,
+            format!(
+                "\"{}\" isn't a stdin. Expected inherit, null or {{ file: ... }}",
+                mode
+            ),
+        )),
+        StdinDefinition::File { file } => Ok(Stdin::File(file.into())),
+    }
 }
 
-impl<'a> From<&'a Yaml> for LazyTask<'a> {
-    fn from(yaml: &'a Yaml) -> Self {
-        LazyTask {
-            yaml_or_task: RefCell::new(YamlOrTask::NotLoaded(yaml)),
-        }
+/**
+ * Variables end up as environment variables so their names have to be valid ones
+ */
+fn check_var_name(name: &str, path: &NodePath) -> Result<(), Diagnostic> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Diagnostic::new(
+            path.clone(),
+            format!(
+                "\"{}\" can't be used as a variable name. Use letters, digits and underscores",
+                name
+            ),
+        ))
     }
 }
 
-impl LazyTask<'_> {
-    fn parse(&self) -> Result<Rc<Script<BashCommand>>, ()> {
-        let mut yaml_or_task = self.yaml_or_task.borrow_mut();
-        match yaml_or_task.deref() {
-            &YamlOrTask::Loaded(ref script) => {
-                return Ok(script.clone());
-            }
-            &YamlOrTask::NotLoaded(ref yaml) => {
-                let script: Rc<Script<BashCommand>> = Rc::new(yaml_to_script(*yaml)?);
-                let script_cell = script.clone();
+fn definition_to_register(
+    definition: &RegisterDefinition,
+    script: ScriptNode,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    let (stdout, status) = match definition {
+        RegisterDefinition::Stdout(name) => (Some(name), None),
+        RegisterDefinition::Table { stdout, status } => (stdout.as_ref(), status.as_ref()),
+    };
+    if stdout.is_none() && status.is_none() {
+        return Err(Diagnostic::new(
+            path.clone(),
+            "Expected a name to register stdout and/or status as",
+        ));
+    }
+    for name in stdout.iter().chain(status.iter()) {
+        check_var_name(name, path)?;
+    }
+    Ok(ScriptNode::Register {
+        stdout: stdout.cloned(),
+        status: status.cloned(),
+        script: Box::new(script),
+    })
+}
 
-                *yaml_or_task = YamlOrTask::Loaded(script);
+fn definition_to_node(
+    definition: &TaskDefinition,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    match definition {
+        TaskDefinition::Command(command_str) => parse_command(command_str, options, path),
+        TaskDefinition::Table(table) => {
+            let task_options;
+            let options = if table.shell.is_some()
+                || table.backend.is_some()
+                || table.strict.is_some()
+                || table.stdin.is_some()
+                || table.env.is_some()
+                || table.cwd.is_some()
+            {
+                let mut overridden_options = options.clone();
+                apply_settings(
+                    &mut overridden_options,
+                    &table.shell,
+                    &table.backend,
+                    table.strict,
+                    path,
+                )?;
+                if let Some(stdin) = &table.stdin {
+                    overridden_options.stdin =
+                        Some(definition_to_stdin(stdin, &overridden_options, path)?);
+                }
+                if let Some(env) = &table.env {
+                    for (name, value) in env {
+                        check_var_name(name, &path.key("env").key(name))?;
+                        overridden_options
+                            .env
+                            .insert(name.clone(), value.to_string());
+                    }
+                }
+                if let Some(cwd) = &table.cwd {
+                    overridden_options.cwd = Some(match &options.cwd {
+                        Some(parent) => Path::new(parent).join(cwd).to_string_lossy().into_owned(),
+                        None => cwd.clone(),
+                    });
+                }
+                task_options = overridden_options;
+                &task_options
+            } else {
+                options
+            };
 
-                return Ok(script_cell);
+            let mut script = table_to_node(table, options, path)?;
+            if let Some(register) = &table.register {
+                script = definition_to_register(register, script, &path.key("register"))?;
+            }
+            if let Some(condition) = &table.condition {
+                Ok(ScriptNode::Conditional {
+                    condition: definition_to_condition(condition, options, &path.key("if"))?,
+                    script: Box::new(script),
+                })
+            } else {
+                Ok(script)
             }
         }
     }
 }
 
-pub struct YamlScriptParser<'a> {
-    pub tasks: HashMap<&'a str, LazyTask<'a>>,
+fn table_to_node(
+    table: &TaskTable,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    if let Some(task) = &table.task {
+        // TODO: Need a splitn
+        parse_alias(task, &path.key("task"))
+    } else if let Some(source) = &table.script {
+        let path = path.key("script");
+        parse_command(
+            source_to_command_str(source, options, &path)?,
+            options,
+            &path,
+        )
+    } else if let Some(series) = &table.series {
+        definitions_to_group(GroupKind::Series, series, options, &path.key("series"))
+    } else if let Some(parallel) = &table.parallel {
+        definitions_to_group(
+            GroupKind::Parallel,
+            parallel,
+            options,
+            &path.key("parallel"),
+        )
+    } else if let Some(pipe) = &table.pipe {
+        definitions_to_group(GroupKind::Pipe, pipe, options, &path.key("pipe"))
+    } else {
+        Err(Diagnostic::new(
+            path.clone(),
+            "Expected one of task, script, series, parallel or pipe",
+        ))
+    }
+}
+
+fn load_task(
+    value: &serde_json::Value,
+    options: &ParseOptions,
+    path: &NodePath,
+) -> Result<ScriptNode, Diagnostic> {
+    definition_to_node(&TaskDefinition::from_value(value, path)?, options, path)
 }
 
-impl<'a> TryFrom<&'a Hash> for YamlScriptParser<'a> {
-    type Error = ();
+enum TaskState {
+    NotLoaded(serde_json::Value),
+    Parsed(ScriptNode),
+    Built(ScriptNode, Arc<Script<DynCommand>>),
+}
 
-    fn try_from(yaml_object: &'a Hash) -> Result<Self, Self::Error> {
-        let tasks_result: Result<HashMap<&'a str, LazyTask<'a>, _>, _> = yaml_object
+/**
+ * A task that only gets parsed and built once something needs it
+ */
+pub struct LazyTask {
+    pub info: TaskInfo,
+    state: Mutex<TaskState>,
+}
+
+impl LazyTask {
+    fn node(&self, name: &str, options: &ParseOptions) -> Result<ScriptNode, Diagnostic> {
+        let mut state = self.state.lock().unwrap();
+        let node = match state.deref() {
+            TaskState::Parsed(node) | TaskState::Built(node, _) => return Ok(node.clone()),
+            TaskState::NotLoaded(value) => load_task(value, options, &NodePath::task(name))?,
+        };
+        *state = TaskState::Parsed(node.clone());
+        Ok(node)
+    }
+
+    fn parse(
+        &self,
+        name: &str,
+        options: &ParseOptions,
+    ) -> Result<Arc<Script<DynCommand>>, Diagnostic> {
+        // Holding the lock while parsing means a task never gets parsed twice when parallel tasks alias it
+        let mut state = self.state.lock().unwrap();
+        let path = NodePath::task(name);
+        let node = match state.deref() {
+            TaskState::Built(_, script) => return Ok(script.clone()),
+            TaskState::Parsed(node) => node.clone(),
+            TaskState::NotLoaded(value) => load_task(value, options, &path)?,
+        };
+        let script = Arc::new(node.to_script(options, &path)?);
+        *state = TaskState::Built(node, script.clone());
+        Ok(script)
+    }
+}
+
+/**
+ * Settings that can be set for the whole file and overridden by individual tasks
+ */
+fn apply_settings(
+    options: &mut ParseOptions,
+    shell: &Option<String>,
+    backend: &Option<String>,
+    strict: Option<bool>,
+    path: &NodePath,
+) -> Result<(), Diagnostic> {
+    if let Some(shell) = shell {
+        options.shell = shell.parse().map_err(|_| {
+            Diagnostic::new(
+                path.key("shell"),
+                format!("\"{}\" isn't a valid shell", shell),
+            )
+        })?;
+    }
+    if let Some(backend) = backend {
+        options.backend = backend.clone();
+    }
+    if let Some(strict) = strict {
+        options.strict = strict;
+    }
+    Ok(())
+}
+
+/**
+ * Owns everything it needs so that it can be shared with spawned tasks and built from a cached plan
+ */
+pub struct ScriptFileParser {
+    pub tasks: BTreeMap<String, LazyTask>,
+    pub options: ParseOptions,
+    /// Where the tasks were loaded from, if known, so that diagnostics can point at the source
+    pub source: Option<Arc<Source>>,
+    pub vars: BTreeMap<String, String>,
+    pub default: Option<String>,
+}
+
+impl ScriptFileParser {
+    pub fn with_source(mut self, source: Arc<Source>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn locate(&self, diagnostic: Diagnostic) -> Diagnostic {
+        match &self.source {
+            Some(source) => source.locate(diagnostic),
+            None => diagnostic,
+        }
+    }
+
+    /**
+     * The file's vars, ready to be shared by a run. A value like $(git rev-parse HEAD) is a command whose output
+     * becomes the value. The commands are created here so any backends they use need to be registered first.
+     */
+    pub fn vars(&self) -> Result<Arc<BTreeMap<String, LazyVar>>, Diagnostic> {
+        let mut vars = BTreeMap::new();
+        for (name, value) in self.vars.iter() {
+            let path = NodePath::task("vars").key(name);
+            check_var_name(name, &path.clone()).map_err(|diagnostic| diagnostic.about_key())?;
+            let var = match value
+                .strip_prefix("$(")
+                .and_then(|value| value.strip_suffix(')'))
+            {
+                Some(command_str) => LazyVar::command(
+                    new_command(command_str, &self.options, &path)?
+                        .to_command(&self.options, &path)?,
+                ),
+                None => LazyVar::literal(value.clone()),
+            };
+            vars.insert(name.clone(), var);
+        }
+        Ok(Arc::new(vars))
+    }
+
+    /**
+     * Makes a backend available to tasks through the "backend" key
+     */
+    pub fn register_backend(&mut self, name: &str, factory: CommandFactory) {
+        self.options.backends.insert(name.to_string(), factory);
+    }
+
+    /**
+     * The tasks that can be run from the command line
+     */
+    pub fn visible_tasks(&self) -> impl Iterator<Item = (&String, &TaskInfo)> {
+        self.tasks
+            .iter()
+            .map(|(name, task)| (name, &task.info))
+            .filter(|(_, info)| !info.hidden)
+    }
+
+    /**
+     * Finds the visible task with the given name or alias
+     */
+    pub fn find_visible_task(&self, name: &str) -> Option<&str> {
+        self.visible_tasks()
+            .find(|(task, info)| *task == name || info.aliases.iter().any(|alias| alias == name))
+            .map(|(task, _)| task.as_str())
+    }
+
+    /**
+     * The task to run when none is given, if the file has one. It has to be a task that could be run by name.
+     */
+    pub fn default_task(&self) -> Option<Result<&str, Diagnostic>> {
+        let default = self.default.as_ref()?;
+        if let Some(task) = self.find_visible_task(default) {
+            return Some(Ok(task));
+        }
+        let path = NodePath::task("default");
+        Some(Err(if self.tasks.contains_key(default) {
+            Diagnostic::new(path, format!("The default task \"{}\" is hidden", default))
+        } else {
+            Diagnostic::missing_task(path, default, &self.task_names())
+        }))
+    }
+
+    /**
+     * Parses every task so that the whole plan can be cached. Fails on the first task that can't be parsed.
+     */
+    pub fn compile(&self) -> Result<PlanFile, Diagnostic> {
+        let mut tasks = BTreeMap::new();
+        for (name, task) in self.tasks.iter() {
+            let script = task
+                .node(name, &self.options)
+                .map_err(|diagnostic| self.locate(diagnostic))?;
+            tasks.insert(
+                name.clone(),
+                TaskNode {
+                    info: task.info.clone(),
+                    script,
+                },
+            );
+        }
+        Ok(PlanFile {
+            shell: self.options.shell.clone(),
+            backend: self.options.backend.clone(),
+            strict: self.options.strict,
+            vars: self.vars.clone(),
+            default: self.default.clone(),
+            tasks,
+        })
+    }
+}
+
+impl TryFrom<&ScriptFile> for ScriptFileParser {
+    type Error = Diagnostic;
+
+    fn try_from(script_file: &ScriptFile) -> Result<Self, Self::Error> {
+        let mut options = ParseOptions::default();
+        apply_settings(
+            &mut options,
+            &script_file.shell,
+            &script_file.backend,
+            script_file.strict,
+            &NodePath::default(),
+        )?;
+
+        let tasks = script_file
+            .tasks
             .iter()
-            .map(|(yaml_name, yaml_value)| -> Result<_, Self::Error> {
-                return Ok((yaml_name.as_str().ok_or(())?, yaml_value.into()));
+            .map(|(name, definition)| {
+                let summary = TaskSummary::of(definition);
+                let info = TaskInfo {
+                    aliases: summary.aliases().into_iter().map(String::from).collect(),
+                    description: summary.description,
+                    hidden: script_file.is_hidden(name),
+                };
+                let task = LazyTask {
+                    info,
+                    state: Mutex::new(TaskState::NotLoaded(definition.clone())),
+                };
+                (name.clone(), task)
             })
             .collect();
-        Ok(YamlScriptParser {
-            tasks: tasks_result?,
+
+        Ok(ScriptFileParser {
+            tasks,
+            options,
+            source: None,
+            vars: script_file
+                .vars
+                .iter()
+                .map(|(name, value)| (name.clone(), value.to_string()))
+                .collect(),
+            default: script_file.default.clone(),
         })
     }
 }
 
-impl ScriptParser<BashCommand> for YamlScriptParser<'_> {
-    fn parse(&self, task_name: &str) -> Result<Rc<Script<BashCommand>>, ()> {
-        self.tasks
-            .get(task_name)
-            .expect(format!("The task {} does not exist", task_name).as_str())
-            .parse()
+impl From<PlanFile> for ScriptFileParser {
+    fn from(plan: PlanFile) -> Self {
+        let tasks = plan
+            .tasks
+            .into_iter()
+            .map(|(name, task)| {
+                let task = LazyTask {
+                    info: task.info,
+                    state: Mutex::new(TaskState::Parsed(task.script)),
+                };
+                (name, task)
+            })
+            .collect();
+
+        ScriptFileParser {
+            tasks,
+            options: ParseOptions {
+                shell: plan.shell,
+                backend: plan.backend,
+                strict: plan.strict,
+                ..ParseOptions::default()
+            },
+            source: None,
+            vars: plan.vars,
+            default: plan.default,
+        }
+    }
+}
+
+impl ScriptParser<DynCommand> for ScriptFileParser {
+    fn parse(&self, task_name: &str) -> Result<Arc<Script<DynCommand>>, Diagnostic> {
+        let task = self.tasks.get(task_name).ok_or_else(|| {
+            Diagnostic::missing_task(NodePath::task(task_name), task_name, &self.task_names())
+        })?;
+        task.parse(task_name, &self.options)
+            .map_err(|diagnostic| self.locate(diagnostic))
+    }
+
+    fn task_names(&self) -> Vec<String> {
+        self.tasks.keys().cloned().collect()
+    }
+
+    fn render(&self, diagnostic: &Diagnostic) -> String {
+        match &self.source {
+            Some(source) => source.render(diagnostic),
+            None => diagnostic.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load<T: serde::de::DeserializeOwned>(yaml_str: &str) -> T {
+        serde_json::from_value(source::parse_yaml(yaml_str).unwrap().0).unwrap()
+    }
+
+    #[test]
+    fn picks_platform_variant() {
+        let source: ScriptSource = load("windows: move a b\ndefault: mv a b");
+        let windows = ParseOptions {
+            platform: Some(Platform::Windows),
+            ..ParseOptions::default()
+        };
+        let linux = ParseOptions {
+            platform: Some(Platform::Linux),
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            source_to_command_str(&source, &windows, &NodePath::task("move")),
+            Ok("move a b")
+        );
+        assert_eq!(
+            source_to_command_str(&source, &linux, &NodePath::task("move")),
+            Ok("mv a b")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_platforms() {
+        let options = ParseOptions {
+            platform: Some(Platform::Linux),
+            ..ParseOptions::default()
+        };
+
+        let path = NodePath::task("move").key("script");
+
+        assert_eq!(
+            source_to_command_str(&load("windows: move a b"), &options, &path)
+                .unwrap_err()
+                .to_string(),
+            "move: script: There's no variant for linux or a default"
+        );
+        assert_eq!(
+            source_to_command_str(&load("solaris: mv a b"), &options, &path)
+                .unwrap_err()
+                .to_string(),
+            "move: script.solaris: \"solaris\" isn't a platform"
+        );
+    }
+
+    #[test]
+    fn dispatches_on_backend() {
+        let mut options = ParseOptions::default();
+        options
+            .backends
+            .insert("upper".to_string(), |source, options| {
+                new_bash_command(&source.to_uppercase(), options)
+            });
+
+        let definition = load("series:\n  - echo a\n  - backend: upper\n    script: echo b");
+        let path = NodePath::task("echo");
+        let script = definition_to_node(&definition, &options, &path)
+            .and_then(|node| node.to_script(&options, &path))
+            .unwrap();
+        let Script::Group(group) = script else {
+            panic!("Expected a group");
+        };
+        let scriptplan_core::CommandGroup::Series(group) = *group else {
+            panic!("Expected a series");
+        };
+        assert!(
+            matches!(group.first.as_ref(), Script::Command(command) if command.to_string() == "echo a")
+        );
+        assert!(
+            matches!(group.rest[0].as_ref(), Script::Command(command) if command.to_string() == "ECHO B")
+        );
+
+        let unknown = load("backend: nope\nscript: echo c");
+        assert!(definition_to_node(&unknown, &options, &path).is_err());
+    }
+
+    fn run_to_string(definition: &str, vars: BTreeMap<String, LazyVar>) -> String {
+        let options = ParseOptions::default();
+        let path = NodePath::task("test");
+        let script = definition_to_node(&load(definition), &options, &path)
+            .and_then(|node| node.to_script(&options, &path))
+            .unwrap();
+        let Script::Command(command) = script else {
+            panic!("Expected a command");
+        };
+        let context = RunContext::new("test").with_vars(Arc::new(vars));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let (stdout, status) = runtime
+            .block_on(context.capture_stdout(|context| async move {
+                command.run(&context, Default::default()).await
+            }))
+            .unwrap();
+        assert!(status.unwrap().success());
+        stdout
+    }
+
+    #[test]
+    fn sets_env_and_cwd_with_vars() {
+        let vars = BTreeMap::from([
+            ("name".to_string(), LazyVar::literal("there")),
+            ("root".to_string(), LazyVar::literal("/")),
+        ]);
+        assert_eq!(
+            run_to_string(
+                "env:\n  GREETING: hi ${name}\ncwd: ${root}\nscript: echo \"$GREETING from $(pwd)\"",
+                vars
+            ),
+            "hi there from /\n"
+        );
+    }
+
+    #[test]
+    fn passes_vars_to_other_interpreters_through_the_environment() {
+        let vars = BTreeMap::from([("name".to_string(), LazyVar::literal("\")\nprint(\"pwned"))]);
+        assert_eq!(
+            run_to_string(
+                "shell: python3\nscript: import os; print(os.environ[\"name\"])",
+                vars
+            ),
+            "\")\nprint(\"pwned\n"
+        );
+    }
+
+    #[test]
+    fn aliases_know_where_they_were_written() {
+        let options = ParseOptions::default();
+        let path = NodePath::task("par");
+        let script =
+            definition_to_node(&load("parallel: [echo one, {task: nope}]"), &options, &path)
+                .and_then(|node| node.to_script(&options, &path))
+                .unwrap();
+        let Script::Group(group) = script else {
+            panic!("Expected a group");
+        };
+        let scriptplan_core::CommandGroup::Parallel(group) = *group else {
+            panic!("Expected a parallel group");
+        };
+        let Script::Alias(alias) = group.rest[0].as_ref() else {
+            panic!("Expected an alias");
+        };
+        assert_eq!(alias.path, path.key("parallel").index(1).key("task"));
+    }
+
+    #[test]
+    fn works_out_required_args() {
+        let script_file: ScriptFile = load(
+            "deploy: echo $2\nstaging: { task: deploy staging }\nfixed: { task: deploy a b }\nrelease:\n  series:\n    - echo $1\n    - task: deploy\n    - task: build $0 $2\nbuild: echo",
+        );
+        let parser = ScriptFileParser::try_from(&script_file).unwrap();
+        let required = |task| scriptplan_core::required_args(task, &parser);
+        assert_eq!(required("deploy"), 2);
+        assert_eq!(required("staging"), 1);
+        assert_eq!(required("fixed"), 0);
+        assert_eq!(required("release"), 3);
     }
 }
@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use scriptplan_core::{Diagnostic, NodePath, Span};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::source::parse_yaml_script_file;
+
+/**
+ * The schema of a script file, regardless of which format it's written in
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScriptFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+    /// Either literal values or $(commands) whose output is used, worked out when first needed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, Scalar>,
+    /// The task (or alias) that runs when none is given outside of a terminal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    /// Every other top level key is a task. They're kept as plain values until they're needed so that a broken task
+    /// only breaks whatever uses it.
+    #[serde(flatten)]
+    pub tasks: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum TaskDefinition {
+    /// Shorthand for { script: ... }
+    Command(String),
+    Table(Box<TaskTable>),
+}
+
+impl TaskDefinition {
+    pub fn from_value(value: &serde_json::Value, path: &NodePath) -> Result<Self, Diagnostic> {
+        TaskDefinition::deserialize(value)
+            .map_err(|err| Diagnostic::new(path.clone(), err.to_string()))
+    }
+}
+
+/**
+ * Serde's message for an untagged enum doesn't say what it was expecting
+ */
+impl<'de> Deserialize<'de> for TaskDefinition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(command) => Ok(TaskDefinition::Command(command)),
+            table @ serde_json::Value::Object(_) => TaskTable::deserialize(table)
+                .map(|table| TaskDefinition::Table(Box::new(table)))
+                .map_err(D::Error::custom),
+            other => Err(D::Error::custom(format!(
+                "Expected a command or a table with one of task, script, series, parallel or pipe but found {}",
+                other
+            ))),
+        }
+    }
+}
+
+/**
+ * What the command line shows for a task. It's read on its own so that a task that doesn't parse still gets listed.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct TaskSummary {
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub hidden: Option<bool>,
+    #[serde(default)]
+    pub aliases: Option<StringOrList>,
+}
+
+impl TaskSummary {
+    pub fn of(definition: &serde_json::Value) -> TaskSummary {
+        // Commands written as a plain string don't have any of these
+        TaskSummary::deserialize(definition).unwrap_or_default()
+    }
+
+    pub fn aliases(&self) -> Vec<&str> {
+        match &self.aliases {
+            None => Vec::new(),
+            Some(StringOrList::One(alias)) => vec![alias.as_str()],
+            Some(StringOrList::Many(aliases)) => aliases.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/**
+ * Exactly one of task, script, series, parallel or pipe is expected to be present
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskTable {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Keeps the task off the command line so that it can only be run through other tasks.
+    /// Defaults to whether the task's name starts with an underscore.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+    /// Other names the task can be run as from the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aliases: Option<StringOrList>,
+    #[serde(rename = "if", default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<ConditionDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// Whether shell scripts stop at the first failure. Applies to the task's children too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+    /// Applies to the task's children too
+    #[serde(
+        default,
+        deserialize_with = "null_stdin",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stdin: Option<StdinDefinition>,
+    /// Extra environment variables, whose values can use ${var}. Applies to the task's children too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<BTreeMap<String, Scalar>>,
+    /// The directory to run in, which can use ${var}. Applies to the task's children too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Saves the task's output for the steps after it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub register: Option<RegisterDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<ScriptSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series: Option<Vec<TaskDefinition>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<Vec<TaskDefinition>>,
+    /// Each member's stdout goes into the next one's stdin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipe: Option<Vec<TaskDefinition>>,
+    /// Kept so that `check` can point out typos instead of them being silently ignored
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScriptSource {
+    Plain(String),
+    /// Keyed by platform name or "default"
+    PerPlatform(BTreeMap<String, String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RegisterDefinition {
+    /// Shorthand for { stdout: ... }
+    Stdout(String),
+    Table {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stdout: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StdinDefinition {
+    /// "inherit" or "null"
+    Mode(String),
+    File {
+        file: String,
+    },
+}
+
+/**
+ * A stdin that's explicitly null is the same as "null" rather than not being set
+ */
+fn null_stdin<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<StdinDefinition>, D::Error> {
+    let definition = Option::<StdinDefinition>::deserialize(deserializer)?;
+    Ok(Some(definition.unwrap_or_else(|| {
+        StdinDefinition::Mode("null".to_string())
+    })))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StringOrList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StringOrList {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            StringOrList::One(value) => vec![value.clone()],
+            StringOrList::Many(values) => values.clone(),
+        }
+    }
+}
+
+/**
+ * Lets env values be written without quotes in formats that have non-string scalars (E.g. CI: true)
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    String(String),
+    Boolean(bool),
+    Integer(i64),
+    Real(f64),
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scalar::String(value) => write!(f, "{}", value),
+            Scalar::Boolean(value) => write!(f, "{}", value),
+            Scalar::Integer(value) => write!(f, "{}", value),
+            Scalar::Real(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnvDefinition {
+    /// The variables only need to be set
+    Names(StringOrList),
+    Values(BTreeMap<String, Scalar>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConditionDefinition {
+    /// Shorthand for { command: ... }
+    Probe(String),
+    Table(Box<ConditionTable>),
+}
+
+/**
+ * Every key that's present has to hold
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConditionTable {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<StringOrList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<StringOrList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<EnvDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<StringOrList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not: Option<ConditionDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub any: Option<Vec<ConditionDefinition>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub all: Option<Vec<ConditionDefinition>>,
+    /// Unknown conditions are an error rather than being treated as always holding
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Read(io::Error),
+    /// The extension isn't one of the supported formats
+    UnknownFormat,
+    /// Points at where parsing stopped when the format's parser says
+    Parse(Diagnostic),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Read(err) => write!(f, "{}", err),
+            LoadError::UnknownFormat => {
+                write!(f, "Script files must end in .yaml, .yml, .toml or .json")
+            }
+            LoadError::Parse(diagnostic) => write!(f, "{}", diagnostic),
+        }
+    }
+}
+
+/**
+ * package.json files keep their tasks in a "scriptplan" section
+ */
+#[derive(Deserialize)]
+struct PackageJson {
+    scriptplan: ScriptFile,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+    PackageJson,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format, LoadError> {
+        if path.file_name().is_some_and(|name| name == "package.json") {
+            return Ok(Format::PackageJson);
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            Some("toml") => Ok(Format::Toml),
+            Some("json") => Ok(Format::Json),
+            _ => Err(LoadError::UnknownFormat),
+        }
+    }
+}
+
+/**
+ * Top level keys that are settings for the whole file rather than tasks
+ */
+pub const RESERVED_KEYS: [&str; 5] = ["shell", "backend", "strict", "vars", "default"];
+
+/**
+ * Keys that say what a task runs. Nothing else in a script file uses them.
+ */
+const RUN_KEYS: [&str; 5] = ["task", "script", "series", "parallel", "pipe"];
+
+/**
+ * A task with a reserved name would be taken as the setting instead, so a setting with any of a task's keys almost
+ * certainly started out as a task
+ */
+pub fn reserved_key_used_as_task<'k>(
+    key: &str,
+    mut value_keys: impl Iterator<Item = &'k str>,
+) -> Option<Diagnostic> {
+    if !RESERVED_KEYS.contains(&key) || !value_keys.any(|key| RUN_KEYS.contains(&key)) {
+        return None;
+    }
+    Some(
+        Diagnostic::new(
+            NodePath::task(key),
+            format!(
+                "\"{}\" is a setting for the whole file so it can't be the name of a task",
+                key
+            ),
+        )
+        .about_key(),
+    )
+}
+
+/**
+ * The first top level entry that's a task with a reserved name, going by the file's plain values
+ */
+pub(crate) fn find_reserved_task(value: &serde_json::Value) -> Option<Diagnostic> {
+    value.as_object()?.iter().find_map(|(key, value)| {
+        let table = value.as_object()?;
+        reserved_key_used_as_task(key, table.keys().map(String::as_str))
+    })
+}
+
+/**
+ * A task with a reserved name stops the file from parsing, and the error the setting gives doesn't explain why
+ */
+fn explain_reserved_keys(err: LoadError, value: Option<serde_json::Value>) -> LoadError {
+    match value.as_ref().and_then(find_reserved_task) {
+        Some(diagnostic) => LoadError::Parse(diagnostic),
+        None => err,
+    }
+}
+
+/**
+ * A line of 0 means the parser didn't say where the problem was
+ */
+pub(crate) fn parse_error(message: String, line: usize, column: usize) -> LoadError {
+    let mut diagnostic = Diagnostic::new(NodePath::default(), message);
+    if line > 0 {
+        diagnostic.span = Some(Span {
+            line,
+            column,
+            length: 1,
+        });
+    }
+    LoadError::Parse(diagnostic)
+}
+
+impl ScriptFile {
+    pub fn parse_str(contents: &str, format: Format) -> Result<ScriptFile, LoadError> {
+        let json_error =
+            |err: serde_json::Error| parse_error(err.to_string(), err.line(), err.column());
+        match format {
+            Format::Yaml => parse_yaml_script_file(contents).map(|(script_file, _)| script_file),
+            Format::Toml => toml::from_str(contents).map_err(|err| {
+                let (line, column) = err.span().map_or((0, 0), |range| {
+                    let before = &contents[..range.start];
+                    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+                    (
+                        before.matches('\n').count() + 1,
+                        before[line_start..].chars().count() + 1,
+                    )
+                });
+                explain_reserved_keys(
+                    parse_error(err.message().to_string(), line, column),
+                    toml::from_str(contents).ok(),
+                )
+            }),
+            Format::Json => serde_json::from_str(contents).map_err(|err| {
+                explain_reserved_keys(json_error(err), serde_json::from_str(contents).ok())
+            }),
+            Format::PackageJson => serde_json::from_str::<PackageJson>(contents)
+                .map(|package| package.scriptplan)
+                .map_err(|err| {
+                    let value = serde_json::from_str::<serde_json::Value>(contents)
+                        .ok()
+                        .map(|package| package["scriptplan"].clone());
+                    explain_reserved_keys(json_error(err), value)
+                }),
+        }
+    }
+
+    pub fn is_hidden(&self, task: &str) -> bool {
+        let hidden = self
+            .tasks
+            .get(task)
+            .and_then(|definition| TaskSummary::of(definition).hidden);
+        hidden.unwrap_or_else(|| task.starts_with('_'))
+    }
+
+    /**
+     * The tasks that can be run from the command line
+     */
+    pub fn visible_tasks(&self) -> impl Iterator<Item = (&String, &serde_json::Value)> {
+        self.tasks.iter().filter(|(name, _)| !self.is_hidden(name))
+    }
+
+    /**
+     * Finds the visible task with the given name or alias
+     */
+    pub fn find_visible_task(&self, name: &str) -> Option<&str> {
+        self.visible_tasks()
+            .find(|(task, definition)| {
+                *task == name || TaskSummary::of(definition).aliases().contains(&name)
+            })
+            .map(|(task, _)| task.as_str())
+    }
+
+    /**
+     * The format is picked based off the file's extension
+     */
+    pub fn load(path: &Path) -> Result<ScriptFile, LoadError> {
+        let format = Format::from_path(path)?;
+        let contents = fs::read_to_string(path).map_err(LoadError::Read)?;
+        ScriptFile::parse_str(&contents, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_share_a_schema() {
+        let yaml = ScriptFile::parse_str(
+            "shell: sh\nbuild: cargo build\ntest:\n  series:\n    - task: build\n    - cargo test\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        let toml = ScriptFile::parse_str(
+            "shell = \"sh\"\nbuild = \"cargo build\"\n[test]\nseries = [{ task = \"build\" }, \"cargo test\"]\n",
+            Format::Toml,
+        )
+        .unwrap();
+        let json = ScriptFile::parse_str(
+            r#"{ "scriptplan": { "shell": "sh", "build": "cargo build", "test": { "series": [{ "task": "build" }, "cargo test"] } } }"#,
+            Format::PackageJson,
+        )
+        .unwrap();
+
+        assert_eq!(yaml.shell.as_deref(), Some("sh"));
+        assert_eq!(yaml.tasks.len(), 2);
+        assert_eq!(yaml, toml);
+        assert_eq!(yaml, json);
+    }
+
+    #[test]
+    fn picks_format_from_extension() {
+        assert_eq!(
+            Format::from_path(Path::new("default.scripts.yml")).unwrap(),
+            Format::Yaml
+        );
+        assert_eq!(
+            Format::from_path(Path::new("./packages/a/package.json")).unwrap(),
+            Format::PackageJson
+        );
+        assert!(Format::from_path(Path::new("scripts.ini")).is_err());
+    }
+
+    #[test]
+    fn explains_tasks_with_reserved_names() {
+        let message = |contents, format| {
+            ScriptFile::parse_str(contents, format)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            message(
+                "shell:\n  script: echo hi\nbuild: cargo build\n",
+                Format::Yaml
+            ),
+            "shell: \"shell\" is a setting for the whole file so it can't be the name of a task"
+        );
+        assert_eq!(
+            message("[default]\ntask = \"build\"\n", Format::Toml),
+            "default: \"default\" is a setting for the whole file so it can't be the name of a task"
+        );
+        // Settings that are just the wrong type are left to serde
+        assert_eq!(
+            message("strict: [true]\n", Format::Yaml),
+            "strict: invalid type: sequence, expected a boolean"
+        );
+    }
+
+    #[test]
+    fn hides_helper_tasks() {
+        let script_file = ScriptFile::parse_str(
+            "_base: echo\nnode-base:\n  hidden: true\n  script: node\n_shown:\n  hidden: false\n  script: echo\ndev:\n  aliases: [d, start]\n  task: node-base\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        let visible: Vec<&String> = script_file.visible_tasks().map(|(name, _)| name).collect();
+        assert_eq!(visible, vec!["_shown", "dev"]);
+        assert_eq!(script_file.find_visible_task("start"), Some("dev"));
+        assert_eq!(script_file.find_visible_task("node-base"), None);
+    }
+}
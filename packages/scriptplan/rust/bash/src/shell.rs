@@ -0,0 +1,287 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+use shellwords::split;
+
+/**
+ * The interpreter that a task's script gets run with
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Shell {
+    Sh,
+    #[default]
+    Bash,
+    Zsh,
+    Fish,
+    Python3,
+    Node,
+    /// Any other interpreter. The script is fed through stdin and arguments are appended to the command line
+    /// so it needs to include whatever tells the interpreter to read from stdin (E.g. "ruby -").
+    /// That means the script can't read from the terminal or a pipe.
+    Custom(Vec<String>),
+}
+
+/**
+ * How the script's body gets to the interpreter. Anything other than stdin leaves stdin free for the script itself.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScriptDelivery {
+    /// It's already one of the arguments
+    Argument,
+    Stdin(String),
+    /// Written to a temporary file whose path goes in front of the arguments
+    File(String),
+}
+
+/**
+ * What needs to be spawned to run a script with a particular shell
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub script: ScriptDelivery,
+}
+
+/**
+ * Single quotes a string for a POSIX shell
+ */
+fn quote(string: &str) -> String {
+    format!("'{}'", string.replace('\'', r"'\''"))
+}
+
+static NEXT_TEMP_SCRIPT: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * A script written out for an interpreter that reads it from a file. It's deleted once dropped.
+ */
+#[derive(Debug)]
+pub struct TempScript {
+    pub path: PathBuf,
+}
+
+impl TempScript {
+    /**
+     * The name is predictable so it's only ever created fresh. Anything already there, like a symlink someone else
+     * planted, is skipped over rather than written through.
+     */
+    pub fn write(body: &str) -> io::Result<TempScript> {
+        loop {
+            let name = format!(
+                "scriptplan-{}-{}",
+                process::id(),
+                NEXT_TEMP_SCRIPT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(mut file) => {
+                    // Owning the path first means the file gets removed even if writing fails
+                    let script = TempScript { path };
+                    file.write_all(body.as_bytes())?;
+                    return Ok(script);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Shell {
+    /**
+     * By default, we want it to be easy for users to be able to apply arguments to the subprocesses that scriptplan executes.
+     * However, if a user explicitly says, say, they want to use arguments in the following order: "$1 $2" then it's probably not a good idea to spread all arguments.
+     * Interpreters that aren't shells get the arguments through their own argv equivalents instead.
+     */
+    fn spread_args(&self, script: &str) -> &'static str {
+        if script.contains('$') {
+            return "";
+        }
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => " $@",
+            Shell::Fish => " $argv",
+            Shell::Python3 | Shell::Node | Shell::Custom(_) => "",
+        }
+    }
+
+    /**
+     * Goes in front of the script, on the same line so that line numbers in errors still match up.
+     * Strict mode stops a script at the first thing that fails rather than carrying on regardless.
+     */
+    pub fn prelude(&self, strict: bool, trace: Option<&str>) -> String {
+        let mut prelude = String::new();
+        if strict {
+            match self {
+                // pipefail isn't POSIX so not every sh has it
+                Shell::Sh => prelude += "set -eu; ",
+                Shell::Bash | Shell::Zsh => prelude += "set -euo pipefail; ",
+                _ => {}
+            }
+        }
+        if let (Some(task), Shell::Sh | Shell::Bash | Shell::Zsh) = (trace, self) {
+            // PS4 has to be set here because bash ignores it in the environment when run as root
+            prelude += &format!("PS4={}; set -x; ", quote(&format!("+ [{}] ", task)));
+        }
+        prelude
+    }
+
+    pub fn invocation(&self, script: &str, forwarded_args: &[&str]) -> Invocation {
+        let body = script.to_string() + self.spread_args(script);
+        let forwarded = forwarded_args.iter().map(|arg| arg.to_string());
+        match self {
+            // The argument after the script becomes $0, which is the shell's name like it would be for an interactive shell
+            Shell::Sh | Shell::Bash | Shell::Zsh => Invocation {
+                program: self.to_string(),
+                args: ["-c".to_string(), body, self.to_string()]
+                    .into_iter()
+                    .chain(forwarded)
+                    .collect(),
+                script: ScriptDelivery::Argument,
+            },
+            Shell::Fish | Shell::Python3 => Invocation {
+                program: self.to_string(),
+                args: ["-c".to_string(), body]
+                    .into_iter()
+                    .chain(forwarded)
+                    .collect(),
+                script: ScriptDelivery::Argument,
+            },
+            // node -e drops the script's slot from process.argv, so arguments wouldn't be where scripts expect them
+            Shell::Node => Invocation {
+                program: self.to_string(),
+                args: forwarded.collect(),
+                script: ScriptDelivery::File(body),
+            },
+            Shell::Custom(command_line) => Invocation {
+                program: command_line[0].clone(),
+                args: command_line[1..].iter().cloned().chain(forwarded).collect(),
+                script: ScriptDelivery::Stdin(body),
+            },
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sh" => Ok(Shell::Sh),
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "python3" => Ok(Shell::Python3),
+            "node" => Ok(Shell::Node),
+            _ => {
+                let command_line = split(name).map_err(|_| ())?;
+                if command_line.is_empty() {
+                    Err(())
+                } else {
+                    Ok(Shell::Custom(command_line))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shell::Sh => write!(f, "sh"),
+            Shell::Bash => write!(f, "bash"),
+            Shell::Zsh => write!(f, "zsh"),
+            Shell::Fish => write!(f, "fish"),
+            Shell::Python3 => write!(f, "python3"),
+            Shell::Node => write!(f, "node"),
+            Shell::Custom(command_line) => write!(f, "{}", command_line.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_scripts_without_following_planted_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let victim = std::env::temp_dir().join(format!("scriptplan-victim-{}", process::id()));
+        fs::write(&victim, "precious").unwrap();
+        let next = NEXT_TEMP_SCRIPT.load(Ordering::Relaxed);
+        let planted: Vec<PathBuf> = (next..next + 3)
+            .map(|n| std::env::temp_dir().join(format!("scriptplan-{}-{}", process::id(), n)))
+            .collect();
+        for path in planted.iter() {
+            std::os::unix::fs::symlink(&victim, path).unwrap();
+        }
+
+        let script = TempScript::write("echo hi");
+        let contents = fs::read_to_string(&victim);
+        for path in planted.iter().chain([&victim]) {
+            let _ = fs::remove_file(path);
+        }
+
+        let script = script.unwrap();
+        assert!(!planted.contains(&script.path));
+        assert_eq!(fs::read_to_string(&script.path).unwrap(), "echo hi");
+        assert_eq!(contents.unwrap(), "precious");
+        let mode = fs::metadata(&script.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn spreads_args_for_shells_only() {
+        let bash = Shell::Bash.invocation("echo", &["a"]);
+        assert_eq!(bash.args, vec!["-c", "echo $@", "bash", "a"]);
+        assert_eq!(bash.script, ScriptDelivery::Argument);
+
+        let explicit = Shell::Sh.invocation("echo $1", &["a"]);
+        assert_eq!(explicit.args, vec!["-c", "echo $1", "sh", "a"]);
+
+        let python = Shell::Python3.invocation("import sys", &["a"]);
+        assert_eq!(python.args, vec!["-c", "import sys", "a"]);
+
+        let node = Shell::Node.invocation("console.log(1)", &["a"]);
+        assert_eq!(node.args, vec!["a"]);
+        assert_eq!(node.script, ScriptDelivery::File("console.log(1)".into()));
+    }
+
+    #[test]
+    fn only_shells_get_a_prelude() {
+        assert_eq!(Shell::Bash.prelude(true, None), "set -euo pipefail; ");
+        assert_eq!(
+            Shell::Sh.prelude(true, Some("it's")),
+            r"set -eu; PS4='+ [it'\''s] '; set -x; "
+        );
+        assert_eq!(Shell::Zsh.prelude(false, None), "");
+        assert_eq!(Shell::Python3.prelude(true, Some("build")), "");
+    }
+
+    #[test]
+    fn parses_custom_interpreters() {
+        let ruby: Shell = "ruby --disable-gems -".parse().unwrap();
+        let invocation = ruby.invocation("puts ARGV", &["a"]);
+        assert_eq!(invocation.program, "ruby");
+        assert_eq!(invocation.args, vec!["--disable-gems", "-", "a"]);
+        assert_eq!(invocation.script, ScriptDelivery::Stdin("puts ARGV".into()));
+        assert!("".parse::<Shell>().is_err());
+    }
+}
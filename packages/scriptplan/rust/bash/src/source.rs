@@ -0,0 +1,689 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use scriptplan_core::{Diagnostic, NodePath, PathSegment, Span};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle, TokenType};
+use yaml_rust::Yaml;
+
+use crate::{find_reserved_task, parse_error, Format, LoadError, ScriptFile};
+
+/**
+ * Where each key and value ended up in the file, keyed by the same paths that diagnostics use
+ */
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    keys: HashMap<NodePath, Span>,
+    values: HashMap<NodePath, Span>,
+}
+
+impl SourceMap {
+    /**
+     * Falls back to the closest ancestor when a path doesn't exist in the file (E.g. a default that was filled in)
+     */
+    pub fn locate(&self, path: &NodePath, on_key: bool) -> Option<Span> {
+        let mut path = path.clone();
+        let mut on_key = on_key;
+        // The whole file isn't any more helpful than no location at all
+        while !path.0.is_empty() {
+            let spans = if on_key { &self.keys } else { &self.values };
+            if let Some(span) = spans.get(&path) {
+                return Some(*span);
+            }
+            path.0.pop();
+            on_key = false;
+        }
+        None
+    }
+
+    /**
+     * package.json files keep everything under a prefix that diagnostic paths don't include
+     */
+    fn strip_prefix(self, prefix: &str) -> SourceMap {
+        let strip = |spans: HashMap<NodePath, Span>| {
+            spans
+                .into_iter()
+                .filter_map(|(path, span)| match path.0.split_first() {
+                    Some((PathSegment::Key(key), rest)) if key == prefix => {
+                        Some((NodePath(rest.to_vec()), span))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        SourceMap {
+            keys: strip(self.keys),
+            values: strip(self.values),
+        }
+    }
+}
+
+enum Frame {
+    Mapping {
+        key: Option<String>,
+        entries: Map<String, Value>,
+    },
+    Sequence {
+        items: Vec<Value>,
+    },
+}
+
+struct OpenCollection {
+    frame: Frame,
+    anchor: usize,
+    /// Collections used as keys don't have a path of their own, and their value isn't kept
+    is_key: bool,
+}
+
+/**
+ * Follows the YAML events to build up the file's values while working out the path of each node, so that a file only
+ * gets parsed once. JSON is valid YAML so its paths get worked out here too.
+ */
+#[derive(Default)]
+struct YamlBuilder {
+    map: SourceMap,
+    path: NodePath,
+    collections: Vec<OpenCollection>,
+    /// The collection that was just opened, until its first entry turns up. Block mappings are only noticed after
+    /// their first key so they start where that key does instead.
+    opened: Option<Span>,
+    /// What each anchor was set to, for aliases to copy
+    anchors: HashMap<usize, Value>,
+    root: Option<Value>,
+}
+
+impl YamlBuilder {
+    /**
+     * Returns whether the node was a mapping key
+     */
+    fn start_node(&mut self, span: Span, key: Option<&str>) -> bool {
+        if let Some(opened) = self.opened.take() {
+            if (span.line, span.column) < (opened.line, opened.column) {
+                self.map.values.insert(self.path.clone(), span);
+            }
+        }
+        match self.collections.last_mut().map(|open| &mut open.frame) {
+            Some(Frame::Mapping {
+                key: current @ None,
+                ..
+            }) => {
+                let key = key.unwrap_or_default().to_string();
+                self.map.keys.insert(self.path.key(&key), span);
+                *current = Some(key);
+                true
+            }
+            Some(Frame::Mapping { key: Some(key), .. }) => {
+                self.path = self.path.key(key);
+                self.map.values.insert(self.path.clone(), span);
+                false
+            }
+            Some(Frame::Sequence { items }) => {
+                self.path = self.path.index(items.len());
+                self.map.values.insert(self.path.clone(), span);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Adds a value that's been finished to whatever contains it
+     */
+    fn end_node(&mut self, value: Value, anchor: usize) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, value.clone());
+        }
+        match self.collections.last_mut().map(|open| &mut open.frame) {
+            Some(Frame::Mapping { key, entries }) => {
+                if let Some(key) = key.take() {
+                    entries.insert(key, value);
+                }
+                self.path.0.pop();
+            }
+            Some(Frame::Sequence { items }) => {
+                items.push(value);
+                self.path.0.pop();
+            }
+            None => self.root = Some(value),
+        }
+    }
+
+    fn start_collection(&mut self, frame: Frame, anchor: usize, mark: Marker) {
+        let span = Span {
+            line: mark.line(),
+            column: mark.col() + 1,
+            length: 1,
+        };
+        let is_key = self.start_node(span, None);
+        self.collections.push(OpenCollection {
+            frame,
+            anchor,
+            is_key,
+        });
+        self.opened = Some(span);
+    }
+
+    fn end_collection(&mut self) {
+        self.opened = None;
+        let Some(open) = self.collections.pop() else {
+            return;
+        };
+        let value = match open.frame {
+            Frame::Mapping { entries, .. } => Value::Object(entries),
+            Frame::Sequence { items } => Value::Array(items),
+        };
+        if open.is_key {
+            if open.anchor > 0 {
+                self.anchors.insert(open.anchor, value);
+            }
+        } else {
+            self.end_node(value, open.anchor);
+        }
+    }
+}
+
+/**
+ * Plain scalars get their type worked out the same way YAML's core schema does. Quoted ones are always strings.
+ */
+fn scalar_value(value: String, style: TScalarStyle, tag: Option<TokenType>) -> Value {
+    let is_str_tag =
+        matches!(&tag, Some(TokenType::Tag(handle, suffix)) if handle == "!!" && suffix == "str");
+    if style != TScalarStyle::Plain || is_str_tag {
+        return Value::String(value);
+    }
+    match Yaml::from_str(&value) {
+        Yaml::Null => Value::Null,
+        Yaml::Boolean(value) => Value::Bool(value),
+        Yaml::Integer(value) => Value::from(value),
+        Yaml::Real(real) => real
+            .parse()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map_or(Value::String(value), Value::Number),
+        _ => Value::String(value),
+    }
+}
+
+impl MarkedEventReceiver for YamlBuilder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let span = |length| Span {
+            line: mark.line(),
+            column: mark.col() + 1,
+            length,
+        };
+        match event {
+            Event::Scalar(value, style, anchor, tag) => {
+                let quotes = match style {
+                    TScalarStyle::SingleQuoted | TScalarStyle::DoubleQuoted => 2,
+                    _ => 0,
+                };
+                // Keys don't end until their value does
+                if !self.start_node(span(value.chars().count() + quotes), Some(&value)) {
+                    self.end_node(scalar_value(value, style, tag), anchor);
+                } else if anchor > 0 {
+                    self.anchors.insert(anchor, Value::String(value));
+                }
+            }
+            Event::Alias(anchor) => {
+                let value = self.anchors.get(&anchor).cloned().unwrap_or(Value::Null);
+                if !self.start_node(span(1), value.as_str()) {
+                    self.end_node(value, 0);
+                }
+            }
+            Event::MappingStart(anchor) => self.start_collection(
+                Frame::Mapping {
+                    key: None,
+                    entries: Map::new(),
+                },
+                anchor,
+                mark,
+            ),
+            Event::SequenceStart(anchor) => {
+                self.start_collection(Frame::Sequence { items: Vec::new() }, anchor, mark)
+            }
+            Event::MappingEnd | Event::SequenceEnd => self.end_collection(),
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Parses YAML (or JSON) into plain values, along with where each of them is in the file
+ */
+pub(crate) fn parse_yaml(contents: &str) -> Result<(Value, SourceMap), LoadError> {
+    let mut builder = YamlBuilder::default();
+    Parser::new(contents.chars())
+        .load(&mut builder, false)
+        .map_err(|err| parse_error(err.to_string(), err.marker().line(), err.marker().col() + 1))?;
+    // An empty file has no tasks rather than being null
+    let value = match builder.root {
+        None | Some(Value::Null) => Value::Object(Map::new()),
+        Some(value) => value,
+    };
+    Ok((value, builder.map))
+}
+
+/**
+ * The values don't remember where they came from, so when one doesn't fit the schema the error gets pinned on the top
+ * level entry it's part of
+ */
+pub(crate) fn parse_yaml_script_file(contents: &str) -> Result<(ScriptFile, SourceMap), LoadError> {
+    let (value, map) = parse_yaml(contents)?;
+    let err = match ScriptFile::deserialize(&value) {
+        Ok(script_file) => return Ok((script_file, map)),
+        Err(err) => err,
+    };
+    let culprit = value.as_object().and_then(|entries| {
+        entries.iter().find_map(|(key, value)| {
+            let entry = Value::Object(Map::from_iter([(key.clone(), value.clone())]));
+            let err = ScriptFile::deserialize(&entry).err()?;
+            Some((NodePath::task(key), err))
+        })
+    });
+    let diagnostic = match (find_reserved_task(&value), culprit) {
+        (Some(diagnostic), _) => Diagnostic {
+            span: map.keys.get(&diagnostic.path).copied(),
+            ..diagnostic
+        },
+        (None, Some((path, err))) => Diagnostic {
+            span: map.values.get(&path).copied(),
+            ..Diagnostic::new(path, err.to_string())
+        },
+        (None, None) => Diagnostic::new(NodePath::default(), err.to_string()),
+    };
+    Err(LoadError::Parse(diagnostic))
+}
+
+fn yaml_source_map(contents: &str) -> SourceMap {
+    let mut builder = YamlBuilder::default();
+    // Anything that doesn't parse gets reported by serde_json instead
+    let _ = Parser::new(contents.chars()).load(&mut builder, false);
+    builder.map
+}
+
+fn byte_span(contents: &str, range: Range<usize>) -> Span {
+    let before = &contents[..range.start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Span {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        length: contents[range].chars().count(),
+    }
+}
+
+struct TomlMapBuilder<'c> {
+    contents: &'c str,
+    map: SourceMap,
+}
+
+impl TomlMapBuilder<'_> {
+    fn insert(
+        &mut self,
+        path: &NodePath,
+        key: Option<&toml_edit::Key>,
+        value: Option<Range<usize>>,
+    ) {
+        if let Some(range) = key.and_then(toml_edit::Key::span) {
+            self.map
+                .keys
+                .insert(path.clone(), byte_span(self.contents, range));
+        }
+        if let Some(range) = value {
+            self.map
+                .values
+                .insert(path.clone(), byte_span(self.contents, range));
+        }
+    }
+
+    fn table(&mut self, path: &NodePath, table: &toml_edit::Table) {
+        for (key, _) in table.iter() {
+            let path = path.key(key);
+            let (key, item) = table.get_key_value(key).unwrap();
+            self.insert(&path, Some(key), item.span());
+            self.item(&path, item);
+        }
+    }
+
+    fn item(&mut self, path: &NodePath, item: &toml_edit::Item) {
+        match item {
+            toml_edit::Item::Table(table) => self.table(path, table),
+            toml_edit::Item::ArrayOfTables(tables) => {
+                for (i, table) in tables.iter().enumerate() {
+                    let path = path.index(i);
+                    self.insert(&path, None, table.span());
+                    self.table(&path, table);
+                }
+            }
+            toml_edit::Item::Value(value) => self.value(path, value),
+            toml_edit::Item::None => {}
+        }
+    }
+
+    fn value(&mut self, path: &NodePath, value: &toml_edit::Value) {
+        match value {
+            toml_edit::Value::InlineTable(table) => {
+                for (key, _) in table.iter() {
+                    let path = path.key(key);
+                    let (key, item) = table.get_key_value(key).unwrap();
+                    self.insert(&path, Some(key), item.span());
+                    self.item(&path, item);
+                }
+            }
+            toml_edit::Value::Array(array) => {
+                for (i, value) in array.iter().enumerate() {
+                    let path = path.index(i);
+                    self.insert(&path, None, value.span());
+                    self.value(&path, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn toml_source_map(contents: &str) -> SourceMap {
+    let mut builder = TomlMapBuilder {
+        contents,
+        map: SourceMap::default(),
+    };
+    if let Ok(document) = toml_edit::ImDocument::parse(contents) {
+        builder.table(&NodePath::default(), document.as_table());
+    }
+    builder.map
+}
+
+/**
+ * Somewhere a task is used by name, either by an alias or as the file's default
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskReference {
+    /// As written, which might be one of the task's alternate names
+    pub task: String,
+    pub path: NodePath,
+    /// Covers just the name, leaving out quotes and arguments
+    pub span: Span,
+}
+
+fn is_reference(path: &NodePath) -> bool {
+    match path.0.as_slice() {
+        [PathSegment::Key(key)] => key == "default",
+        [_, .., PathSegment::Key(key)] => key == "task",
+        _ => false,
+    }
+}
+
+/**
+ * A script file's contents, kept around so that diagnostics can point at where things went wrong
+ */
+#[derive(Debug)]
+pub struct Source {
+    /// What the file gets called in diagnostics
+    pub name: String,
+    pub contents: String,
+    pub format: Format,
+    /// Only worked out once a diagnostic needs it since runs from a cached plan otherwise never look at the contents
+    map: OnceLock<SourceMap>,
+}
+
+impl Source {
+    pub fn new(name: impl Into<String>, contents: impl Into<String>, format: Format) -> Self {
+        Source {
+            name: name.into(),
+            contents: contents.into(),
+            format,
+            map: OnceLock::new(),
+        }
+    }
+
+    fn map(&self) -> &SourceMap {
+        self.map.get_or_init(|| match self.format {
+            Format::Yaml | Format::Json => yaml_source_map(&self.contents),
+            Format::PackageJson => yaml_source_map(&self.contents).strip_prefix("scriptplan"),
+            Format::Toml => toml_source_map(&self.contents),
+        })
+    }
+
+    /**
+     * The format is picked based off the file's extension
+     */
+    pub fn read(path: &Path) -> Result<Source, LoadError> {
+        let format = Format::from_path(path)?;
+        let contents = fs::read_to_string(path).map_err(LoadError::Read)?;
+        Ok(Source::new(path.display().to_string(), contents, format))
+    }
+
+    pub fn parse(&self) -> Result<ScriptFile, LoadError> {
+        match self.format {
+            Format::Yaml => {
+                let (script_file, map) = parse_yaml_script_file(&self.contents)?;
+                let _ = self.map.set(map);
+                Ok(script_file)
+            }
+            _ => ScriptFile::parse_str(&self.contents, self.format),
+        }
+    }
+
+    /**
+     * Where the task's name is in the file
+     */
+    pub fn task_span(&self, name: &str) -> Option<Span> {
+        self.map().keys.get(&NodePath::task(name)).copied()
+    }
+
+    /**
+     * Every alias and default in the file, in the order they appear
+     */
+    pub fn task_references(&self) -> Vec<TaskReference> {
+        let lines: Vec<&str> = self.contents.lines().collect();
+        let mut references: Vec<TaskReference> = self
+            .map()
+            .values
+            .iter()
+            .filter(|(path, _)| is_reference(path))
+            .filter_map(|(path, span)| {
+                let line = lines.get(span.line - 1)?;
+                let mut column = span.column;
+                let mut value = line.chars().skip(column - 1).peekable();
+                if value.next_if(|c| *c == '"' || *c == '\'').is_some() {
+                    column += 1;
+                }
+                let task: String = value
+                    .take_while(|c| !c.is_whitespace() && !matches!(c, '"' | '\'' | ',' | '}'))
+                    .collect();
+                if task.is_empty() {
+                    return None;
+                }
+                Some(TaskReference {
+                    span: Span {
+                        line: span.line,
+                        column,
+                        length: task.chars().count(),
+                    },
+                    task,
+                    path: path.clone(),
+                })
+            })
+            .collect();
+        references.sort_by_key(|reference| (reference.span.line, reference.span.column));
+        references
+    }
+
+    /**
+     * Fills in where the diagnostic's node is, if it isn't already known
+     */
+    pub fn locate(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if diagnostic.span.is_none() {
+            diagnostic.span = self.map().locate(&diagnostic.path, diagnostic.on_key);
+        }
+        diagnostic
+    }
+
+    /**
+     * Shows the diagnostic along with the line it's on and a caret underneath the offending node
+     */
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let span = match self.locate(diagnostic.clone()).span {
+            Some(span) => span,
+            None => return format!("{}: {}", self.name, diagnostic),
+        };
+        let line = self.contents.lines().nth(span.line - 1).unwrap_or("");
+        let line_length = line.chars().count();
+        let column = span.column.min(line_length + 1);
+        let length = span.length.clamp(1, (line_length + 1 - column).max(1));
+
+        let gutter = " ".repeat(span.line.to_string().len());
+        format!(
+            "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+            self.name,
+            span.line,
+            span.column,
+            diagnostic,
+            gutter,
+            span.line,
+            line,
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(length)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_paths_in_each_format() {
+        let path = NodePath::task("test").key("series").index(0).key("task");
+
+        let yaml = Source::new(
+            "scripts.yaml",
+            "build: cargo build\ntest:\n  series:\n    - task: biuld\n",
+            Format::Yaml,
+        );
+        let toml = Source::new(
+            "scripts.toml",
+            "build = \"cargo build\"\n[test]\nseries = [{ task = \"biuld\" }]\n",
+            Format::Toml,
+        );
+        let json = Source::new(
+            "package.json",
+            "{\n  \"scriptplan\": {\n    \"test\": { \"series\": [{ \"task\": \"biuld\" }] }\n  }\n}\n",
+            Format::PackageJson,
+        );
+
+        let locate = |source: &Source, on_key| source.map().locate(&path, on_key);
+        assert_eq!(
+            locate(&yaml, false),
+            Some(Span {
+                line: 4,
+                column: 13,
+                length: 5
+            })
+        );
+        assert_eq!(locate(&yaml, true).unwrap().column, 7);
+        assert_eq!(yaml.map().locate(&NodePath::task("nope"), false), None);
+        assert_eq!(
+            locate(&toml, false),
+            Some(Span {
+                line: 3,
+                column: 20,
+                length: 7
+            })
+        );
+        assert_eq!(
+            locate(&json, false),
+            Some(Span {
+                line: 3,
+                column: 36,
+                length: 7
+            })
+        );
+    }
+
+    #[test]
+    fn gets_values_and_spans_from_one_parse() {
+        let source = Source::new(
+            "scripts.yaml",
+            "vars:\n  count: 3\n  quoted: '3'\n  flag: !!str true\nbuild: &build cargo build\nrelease: *build\n",
+            Format::Yaml,
+        );
+        let script_file = source.parse().unwrap();
+        assert!(source.map.get().is_some());
+        assert_eq!(script_file.vars["count"], crate::Scalar::Integer(3));
+        assert_eq!(
+            script_file.vars["quoted"],
+            crate::Scalar::String("3".to_string())
+        );
+        assert_eq!(
+            script_file.vars["flag"],
+            crate::Scalar::String("true".to_string())
+        );
+        assert_eq!(script_file.tasks["build"], script_file.tasks["release"]);
+
+        let broken = Source::new(
+            "scripts.yaml",
+            "build: cargo build\nstrict: [true]\n",
+            Format::Yaml,
+        );
+        let Err(LoadError::Parse(diagnostic)) = broken.parse() else {
+            panic!("Expected a parse error");
+        };
+        assert_eq!(diagnostic.path, NodePath::task("strict"));
+        assert_eq!(
+            diagnostic.span.map(|span| (span.line, span.column)),
+            Some((2, 9))
+        );
+    }
+
+    #[test]
+    fn renders_a_caret_under_the_node() {
+        let source = Source::new("scripts.yaml", "test:\n  task: biuld\n", Format::Yaml);
+        let diagnostic = Diagnostic::new(NodePath::task("test").key("task"), "Oops");
+        assert_eq!(
+            source.render(&diagnostic),
+            "scripts.yaml:2:9: test: task: Oops\n  |\n2 |   task: biuld\n  |         ^^^^^"
+        );
+    }
+
+    #[test]
+    fn finds_where_tasks_are_referenced() {
+        let source = Source::new(
+            "scripts.yaml",
+            "default: build\nbuild: cargo build\ntest:\n  series:\n    - task: \"build --release\"\n    - cargo test\n",
+            Format::Yaml,
+        );
+        let references: Vec<(String, Span)> = source
+            .task_references()
+            .into_iter()
+            .map(|reference| (reference.task, reference.span))
+            .collect();
+        assert_eq!(
+            references,
+            vec![
+                (
+                    "build".to_string(),
+                    Span {
+                        line: 1,
+                        column: 10,
+                        length: 5
+                    }
+                ),
+                (
+                    "build".to_string(),
+                    Span {
+                        line: 5,
+                        column: 14,
+                        length: 5
+                    }
+                ),
+            ]
+        );
+        assert_eq!(source.task_span("test").unwrap().line, 3);
+    }
+}
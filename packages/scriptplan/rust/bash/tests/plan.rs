@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use scriptplan_bash::{BashCommand, Shell};
+use scriptplan_core::{
+    alias, parallel, pipe, series, Alias, CommandGroup, Plan, RunContext, Script, ScriptGroup,
+    Stdin,
+};
+
+/**
+ * Runs a task on a runtime of its own, giving back what it printed and how it exited
+ */
+fn run(plan: Plan<BashCommand>, task: &str) -> (String, Result<Option<i32>, ()>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    let (stdout, result) = runtime
+        .block_on(RunContext::new(task).capture_stdout(|context| async move {
+            plan.run_in(&context, task, VecDeque::new()).await
+        }))
+        .unwrap();
+    (stdout, result.map(|status| status.code()))
+}
+
+fn sh(script: &str) -> BashCommand {
+    BashCommand::from(script)
+}
+
+#[test]
+fn pipes_members_together() {
+    // Running a plan consumes it
+    let plan = || {
+        Plan::new()
+            .task("upper", sh("tr a-z A-Z"))
+            .task(
+                "sorted",
+                pipe![
+                    sh("printf 'b\\na\\n'"),
+                    series![sh("sort"), sh("echo done")],
+                    alias("upper"),
+                ],
+            )
+            .task("failed", pipe![sh("exit 3"), sh("exit 4"), sh("cat")])
+            .task("ok", pipe![sh("exit 0"), sh("cat")])
+    };
+
+    assert_eq!(
+        run(plan(), "sorted"),
+        ("A\nB\nDONE\n".to_string(), Ok(Some(0)))
+    );
+    // The rightmost failure decides the status
+    assert_eq!(run(plan(), "failed"), (String::new(), Ok(Some(4))));
+    assert_eq!(run(plan(), "ok"), (String::new(), Ok(Some(0))));
+}
+
+#[test]
+fn registers_output_for_later_steps() {
+    let plan = Plan::new().task("write", sh("echo \"$1 $version\"")).task(
+        "release",
+        series![
+            Script::from(sh("echo '  1.2  '")).register("version"),
+            Alias::new("write", ["v${version}"]),
+        ],
+    );
+
+    // The registered output isn't printed
+    assert_eq!(
+        run(plan, "release"),
+        ("v1.2 1.2\n".to_string(), Ok(Some(0)))
+    );
+}
+
+#[test]
+fn bailing_series_stop_at_the_first_failure() {
+    let group = |bail| {
+        Script::from(CommandGroup::Series(ScriptGroup {
+            bail,
+            ..ScriptGroup::new(sh("exit 2").into(), vec![sh("echo after").into()])
+        }))
+    };
+    let plan = || {
+        Plan::new()
+            .task("carry-on", group(false))
+            .task("bail", group(true))
+    };
+
+    assert_eq!(
+        run(plan(), "carry-on"),
+        ("after\n".to_string(), Ok(Some(2)))
+    );
+    assert_eq!(run(plan(), "bail"), (String::new(), Ok(Some(2))));
+}
+
+#[test]
+fn parallel_groups_wait_for_broken_branches() {
+    let plan = || {
+        Plan::new()
+            .task("broken-last", parallel![sh("echo ran"), alias("missing")])
+            .task("broken-first", parallel![alias("missing"), sh("echo ran")])
+            .task("failed", parallel![sh("exit 3"), alias("missing")])
+            .task("all-broken", parallel![alias("missing"), alias("missing")])
+    };
+
+    // The branches that work still get to finish
+    assert_eq!(run(plan(), "broken-last"), ("ran\n".to_string(), Err(())));
+    assert_eq!(run(plan(), "broken-first"), ("ran\n".to_string(), Err(())));
+    assert_eq!(run(plan(), "failed"), (String::new(), Ok(Some(3))));
+    assert_eq!(run(plan(), "all-broken"), (String::new(), Err(())));
+}
+
+#[test]
+fn pipes_share_the_terminal_with_interactive_branches() {
+    let interactive = BashCommand {
+        stdin: Some(Stdin::Inherit),
+        ..BashCommand::from("true")
+    };
+    let plan = Plan::new().task(
+        "ci",
+        parallel![
+            // More than fits in the pipe, so the first member can't finish until the last one starts reading
+            pipe![
+                sh("head -c 1000000 /dev/zero"),
+                series![sh("sleep 0.2"), sh("wc -c | tr -d ' '")],
+            ],
+            // Asks for the terminal while the first member is running and before the last one starts reading
+            series![sh("sleep 0.1"), interactive],
+        ],
+    );
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || sender.send(run(plan, "ci")));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(10)),
+        Ok(("1000000\n".to_string(), Ok(Some(0))))
+    );
+}
+
+#[test]
+fn fails_when_the_interpreter_stops_reading_the_script() {
+    // Too much to fit in the pipe before the interpreter exits without reading any of it
+    let ignored = BashCommand {
+        shell: Shell::Custom(vec!["true".to_string()]),
+        ..BashCommand::from("#".repeat(1_000_000))
+    };
+    let plan = Plan::new().task("ignored", ignored);
+    assert_eq!(run(plan, "ignored"), (String::new(), Err(())));
+}
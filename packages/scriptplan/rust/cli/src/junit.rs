@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use scriptplan_bash::scriptplan_core::{OutputStream, Reporter, RunContext, RunEvent};
+
+struct TestCase {
+    /// The tasks the command was run through, outermost first
+    class_name: String,
+    name: String,
+    duration: Duration,
+    outcome: Outcome,
+    stdout: String,
+    stderr: String,
+}
+
+enum Outcome {
+    /// Still going when the report was written, which counts as a failure
+    Running,
+    Passed,
+    /// The status is None if the command was killed by a signal
+    Failed(Option<i32>),
+    Skipped,
+}
+
+#[derive(Default)]
+struct Cases {
+    task: Option<String>,
+    duration: Duration,
+    cases: Vec<TestCase>,
+    /// Commands that have started but not finished yet, by id
+    running: HashMap<usize, usize>,
+}
+
+/**
+ * Collects a testcase for each command that gets run so they can be written out as JUnit XML.
+ * Skipped scripts are included as skipped testcases.
+ */
+#[derive(Default)]
+pub struct JUnitReporter {
+    cases: Mutex<Cases>,
+}
+
+fn escape(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '&' => Some("&amp;".to_string()),
+            '<' => Some("&lt;".to_string()),
+            '>' => Some("&gt;".to_string()),
+            '"' => Some("&quot;".to_string()),
+            // XML can't contain most control characters, even escaped. This mostly drops colour codes.
+            '\t' | '\n' | '\r' => Some(c.to_string()),
+            c if c.is_control() => None,
+            c => Some(c.to_string()),
+        })
+        .collect()
+}
+
+impl JUnitReporter {
+    pub fn to_xml(&self) -> String {
+        let cases = self.cases.lock().unwrap();
+        let failures = cases
+            .cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Running | Outcome::Failed(_)))
+            .count();
+        let skipped = cases
+            .cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Skipped))
+            .count();
+        let task = escape(cases.task.as_deref().unwrap_or_default());
+        let seconds = cases.duration.as_secs_f64();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            task,
+            cases.cases.len(),
+            failures,
+            skipped,
+            seconds
+        );
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            task,
+            cases.cases.len(),
+            failures,
+            skipped,
+            seconds
+        );
+        for case in cases.cases.iter() {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                escape(&case.class_name),
+                escape(&case.name),
+                case.duration.as_secs_f64()
+            );
+            let message = match case.outcome {
+                Outcome::Skipped => {
+                    xml.push_str(">\n      <skipped/>\n    </testcase>\n");
+                    continue;
+                }
+                Outcome::Passed => {
+                    xml.push_str("/>\n");
+                    continue;
+                }
+                Outcome::Running => "Did not finish".to_string(),
+                Outcome::Failed(Some(code)) => format!("Exited with status {}", code),
+                Outcome::Failed(None) => "Did not exit normally".to_string(),
+            };
+            let _ = writeln!(xml, ">\n      <failure message=\"{}\"/>", message);
+            if !case.stdout.is_empty() {
+                let _ = writeln!(
+                    xml,
+                    "      <system-out>{}</system-out>",
+                    escape(&case.stdout)
+                );
+            }
+            if !case.stderr.is_empty() {
+                let _ = writeln!(
+                    xml,
+                    "      <system-err>{}</system-err>",
+                    escape(&case.stderr)
+                );
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_xml())
+    }
+}
+
+impl Reporter for JUnitReporter {
+    fn report(&self, context: &RunContext, event: RunEvent) {
+        let mut cases = self.cases.lock().unwrap();
+        let class_name = context.alias_chain.join(".");
+        match event {
+            RunEvent::TaskFinished { duration, .. } if context.alias_chain.len() == 1 => {
+                cases.duration = duration;
+            }
+            RunEvent::TaskStarted { task } if cases.task.is_none() => cases.task = Some(task),
+            RunEvent::CommandStarted { id, command, .. } => {
+                let index = cases.cases.len();
+                cases.running.insert(id, index);
+                cases.cases.push(TestCase {
+                    class_name,
+                    name: command,
+                    duration: Duration::ZERO,
+                    outcome: Outcome::Running,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                });
+            }
+            RunEvent::Output {
+                id: Some(id),
+                stream,
+                chunk,
+            } => {
+                if let Some(&index) = cases.running.get(&id) {
+                    let case = &mut cases.cases[index];
+                    match stream {
+                        OutputStream::Stdout => case.stdout.push_str(&chunk),
+                        OutputStream::Stderr => case.stderr.push_str(&chunk),
+                    }
+                }
+            }
+            RunEvent::CommandFinished {
+                id,
+                status,
+                duration,
+            } => {
+                if let Some(index) = cases.running.remove(&id) {
+                    let case = &mut cases.cases[index];
+                    case.outcome = match status {
+                        Some(0) => Outcome::Passed,
+                        status => Outcome::Failed(status),
+                    };
+                    case.duration = duration;
+                }
+            }
+            RunEvent::Skipped { condition, .. } => cases.cases.push(TestCase {
+                class_name,
+                name: format!("if {}", condition),
+                duration: Duration::ZERO,
+                outcome: Outcome::Skipped,
+                stdout: String::new(),
+                stderr: String::new(),
+            }),
+            _ => {}
+        }
+    }
+
+    fn captures_output(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_command(
+        reporter: &JUnitReporter,
+        context: &RunContext,
+        command: &str,
+        output: &[(OutputStream, &str)],
+        status: Option<i32>,
+    ) {
+        let id = context.next_id();
+        let command_context = context.start_command(&command);
+        reporter.report(
+            &command_context,
+            RunEvent::CommandStarted {
+                id,
+                task: context.task(),
+                command: command.to_string(),
+            },
+        );
+        for (stream, chunk) in output {
+            reporter.report(
+                &command_context,
+                RunEvent::Output {
+                    id: Some(id),
+                    stream: *stream,
+                    chunk: chunk.to_string(),
+                },
+            );
+        }
+        reporter.report(
+            &command_context,
+            RunEvent::CommandFinished {
+                id,
+                status,
+                duration: Duration::from_millis(1500),
+            },
+        );
+    }
+
+    #[test]
+    fn writes_a_testcase_for_each_command() {
+        let reporter = JUnitReporter::default();
+        let root = RunContext::new("ci");
+        let lint = root.enter_alias("lint").unwrap();
+        reporter.report(
+            &root,
+            RunEvent::TaskStarted {
+                task: "ci".to_string(),
+            },
+        );
+        reporter.report(
+            &lint,
+            RunEvent::TaskStarted {
+                task: "lint".to_string(),
+            },
+        );
+        run_command(
+            &reporter,
+            &lint,
+            "cargo clippy",
+            &[(OutputStream::Stdout, "all good\n")],
+            Some(0),
+        );
+        run_command(
+            &reporter,
+            &root,
+            "test \"$1\" -lt 3 && echo '<done>'",
+            &[
+                (OutputStream::Stdout, "partly "),
+                (OutputStream::Stdout, "\u{1b}[31mred\u{1b}[0m & done\n"),
+                (OutputStream::Stderr, "expected <3>\n"),
+            ],
+            Some(2),
+        );
+        run_command(&reporter, &root, "sleep 100", &[], None);
+        reporter.report(
+            &root,
+            RunEvent::Skipped {
+                task: "ci".to_string(),
+                condition: "os: windows".to_string(),
+            },
+        );
+        reporter.report(
+            &root,
+            RunEvent::TaskFinished {
+                task: "ci".to_string(),
+                status: Some(2),
+                duration: Duration::from_secs(3),
+            },
+        );
+
+        assert_eq!(
+            reporter.to_xml(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="ci" tests="4" failures="2" skipped="1" time="3.000">
+  <testsuite name="ci" tests="4" failures="2" skipped="1" time="3.000">
+    <testcase classname="ci.lint" name="cargo clippy" time="1.500"/>
+    <testcase classname="ci" name="test &quot;$1&quot; -lt 3 &amp;&amp; echo '&lt;done&gt;'" time="1.500">
+      <failure message="Exited with status 2"/>
+      <system-out>partly [31mred[0m &amp; done
+</system-out>
+      <system-err>expected &lt;3&gt;
+</system-err>
+    </testcase>
+    <testcase classname="ci" name="sleep 100" time="1.500">
+      <failure message="Did not exit normally"/>
+    </testcase>
+    <testcase classname="ci" name="if os: windows" time="0.000">
+      <skipped/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn fails_commands_that_never_finished() {
+        let reporter = JUnitReporter::default();
+        let context = RunContext::new("serve");
+        reporter.report(
+            &context,
+            RunEvent::CommandStarted {
+                id: context.next_id(),
+                task: "serve".to_string(),
+                command: "npm start".to_string(),
+            },
+        );
+
+        let xml = reporter.to_xml();
+        assert!(
+            xml.contains("tests=\"1\" failures=\"1\" skipped=\"0\""),
+            "{}",
+            xml
+        );
+        assert!(
+            xml.contains("<failure message=\"Did not finish\"/>"),
+            "{}",
+            xml
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::path::Path;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationType, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, GotoDefinition, HoverRequest, References, Request as RequestType,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse, DiagnosticSeverity,
+    GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability, Location, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+use scriptplan_bash::scriptplan_core::{
+    format_plan, Diagnostic, NodePath, RunContext, ScriptParser, Span,
+};
+use scriptplan_bash::{
+    check, Format, LoadError, ScriptFile, ScriptFileParser, Source, TaskSummary,
+};
+
+use crate::{check_with_builtins, register_backends};
+
+type ServeError = Box<dyn Error + Send + Sync>;
+
+/**
+ * LSP positions count UTF-16 code units from 0 while spans count characters from 1
+ */
+fn to_position(contents: &str, line: usize, column: usize) -> Position {
+    let text = contents.lines().nth(line - 1).unwrap_or_default();
+    let character: usize = text.chars().take(column - 1).map(char::len_utf16).sum();
+    Position::new((line - 1) as u32, character as u32)
+}
+
+fn to_range(contents: &str, span: Span) -> Range {
+    Range::new(
+        to_position(contents, span.line, span.column),
+        to_position(contents, span.line, span.column + span.length),
+    )
+}
+
+/**
+ * The line and column a position is at, both starting from 1
+ */
+fn from_position(contents: &str, position: Position) -> (usize, usize) {
+    let text = contents
+        .lines()
+        .nth(position.line as usize)
+        .unwrap_or_default();
+    let mut units = 0;
+    let mut column = 1;
+    for c in text.chars() {
+        if units >= position.character as usize {
+            break;
+        }
+        units += c.len_utf16();
+        column += 1;
+    }
+    (position.line as usize + 1, column)
+}
+
+/**
+ * Whether the text before the cursor ends partway through the task name of a `task:` or `default:` value
+ */
+fn completes_task(before: &str) -> bool {
+    let quote_or_space = |c: char| c.is_whitespace() || c == '"' || c == '\'';
+    let name_start = before
+        .char_indices()
+        .rfind(|&(_, c)| quote_or_space(c) || c == ':' || c == '=')
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let key = match before[..name_start]
+        .trim_end_matches(quote_or_space)
+        .strip_suffix([':', '='])
+    {
+        Some(key) => key.trim_end_matches(quote_or_space),
+        None => return false,
+    };
+    ["task", "default"].iter().any(|name| {
+        key.strip_suffix(name).is_some_and(|lead| {
+            !lead.ends_with(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        })
+    })
+}
+
+fn new_parser(script_file: &ScriptFile) -> Result<ScriptFileParser, Diagnostic> {
+    let mut parser = ScriptFileParser::try_from(script_file)?;
+    register_backends(&mut parser);
+    Ok(parser)
+}
+
+/**
+ * An open script file. The last version that parsed is kept so that tasks can still be completed while the file is
+ * half written.
+ */
+struct Document {
+    source: Source,
+    script_file: Option<ScriptFile>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    fn new(uri: &Url, text: String, last_parsed: Option<ScriptFile>) -> Document {
+        // Editors might not have saved the file yet, so anything without a known extension is treated as YAML
+        let format = Format::from_path(Path::new(uri.path())).unwrap_or(Format::Yaml);
+        let source = Source::new(uri.path(), text, format);
+        let (script_file, diagnostics) = match source.parse() {
+            Ok(script_file) => {
+                let diagnostics = match new_parser(&script_file) {
+                    Ok(parser) => check_with_builtins(&script_file, &parser),
+                    Err(diagnostic) => vec![diagnostic],
+                };
+                (Some(script_file), diagnostics)
+            }
+            Err(LoadError::Parse(diagnostic)) => (last_parsed, vec![diagnostic]),
+            Err(err) => (
+                last_parsed,
+                vec![Diagnostic::new(NodePath::default(), err.to_string())],
+            ),
+        };
+        Document {
+            source,
+            script_file,
+            diagnostics,
+        }
+    }
+
+    fn lsp_diagnostics(&self) -> Vec<lsp_types::Diagnostic> {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let range = match self.source.locate(diagnostic.clone()).span {
+                    Some(span) => to_range(&self.source.contents, span),
+                    None => Range::default(),
+                };
+                lsp_types::Diagnostic {
+                    range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("scriptplan".to_string()),
+                    message: diagnostic.message.clone(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * The task that's defined or referenced wherever the position is
+     */
+    fn task_at(&self, position: Position) -> Option<String> {
+        let (line, column) = from_position(&self.source.contents, position);
+        let contains = |span: Span| {
+            span.line == line && (span.column..=span.column + span.length).contains(&column)
+        };
+        if let Some(reference) = self
+            .source
+            .task_references()
+            .into_iter()
+            .find(|reference| contains(reference.span))
+        {
+            return Some(reference.task);
+        }
+        self.script_file
+            .as_ref()?
+            .tasks
+            .keys()
+            .find(|task| self.source.task_span(task).is_some_and(contains))
+            .cloned()
+    }
+
+    fn location(&self, uri: &Url, span: Span) -> Location {
+        Location::new(uri.clone(), to_range(&self.source.contents, span))
+    }
+
+    fn completions(&self, position: Position) -> Vec<CompletionItem> {
+        let (line, column) = from_position(&self.source.contents, position);
+        let text = self
+            .source
+            .contents
+            .lines()
+            .nth(line - 1)
+            .unwrap_or_default();
+        let before: String = text.chars().take(column - 1).collect();
+        let script_file = match &self.script_file {
+            Some(script_file) if completes_task(&before) => script_file,
+            _ => return Vec::new(),
+        };
+        script_file
+            .tasks
+            .iter()
+            .map(|(task, definition)| CompletionItem {
+                label: task.clone(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: TaskSummary::of(definition).description,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn definition(&self, uri: &Url, position: Position) -> Option<GotoDefinitionResponse> {
+        let task = self.task_at(position)?;
+        let span = self.source.task_span(&task)?;
+        Some(GotoDefinitionResponse::Scalar(self.location(uri, span)))
+    }
+
+    fn references(
+        &self,
+        uri: &Url,
+        position: Position,
+        include_declaration: bool,
+    ) -> Option<Vec<Location>> {
+        let task = self.task_at(position)?;
+        let declaration = self.source.task_span(&task).filter(|_| include_declaration);
+        let references = self
+            .source
+            .task_references()
+            .into_iter()
+            .filter(|reference| reference.task == task)
+            .map(|reference| reference.span);
+        Some(
+            declaration
+                .into_iter()
+                .chain(references)
+                .map(|span| self.location(uri, span))
+                .collect(),
+        )
+    }
+
+    /**
+     * The task's description followed by what it would run, like `--dry-run` shows
+     */
+    fn hover(&self, position: Position) -> Option<Hover> {
+        let task = self.task_at(position)?;
+        let script_file = self.script_file.as_ref()?;
+        let mut value = format!("**{}**", task);
+        if let Some(description) = TaskSummary::of(script_file.tasks.get(&task)?).description {
+            value += &format!("\n\n{}", description);
+        }
+        let parser = new_parser(script_file).ok();
+        if let Some((parser, script)) = parser.and_then(|parser| {
+            let script = parser.parse(&task).ok()?;
+            Some((parser, script))
+        }) {
+            let plan = format_plan(&script, &parser, &RunContext::new(&task));
+            value += &format!("\n\n```sh\n{}\n```", plan.trim_end());
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+}
+
+fn respond<R: RequestType>(
+    request: Request,
+    handler: impl FnOnce(R::Params) -> R::Result,
+) -> Response {
+    match serde_json::from_value::<R::Params>(request.params) {
+        Ok(params) => Response::new_ok(request.id, handler(params)),
+        Err(err) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, err.to_string()),
+    }
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn handle_request(&self, request: Request) -> Response {
+        let documents = &self.documents;
+        match request.method.as_str() {
+            Completion::METHOD => respond::<Completion>(request, |params| {
+                let position = params.text_document_position;
+                let document = documents.get(&position.text_document.uri)?;
+                Some(CompletionResponse::Array(
+                    document.completions(position.position),
+                ))
+            }),
+            GotoDefinition::METHOD => respond::<GotoDefinition>(request, |params| {
+                let position = params.text_document_position_params;
+                let uri = &position.text_document.uri;
+                documents.get(uri)?.definition(uri, position.position)
+            }),
+            References::METHOD => respond::<References>(request, |params| {
+                let position = params.text_document_position;
+                let uri = &position.text_document.uri;
+                documents.get(uri)?.references(
+                    uri,
+                    position.position,
+                    params.context.include_declaration,
+                )
+            }),
+            HoverRequest::METHOD => respond::<HoverRequest>(request, |params| {
+                let position = params.text_document_position_params;
+                documents
+                    .get(&position.text_document.uri)?
+                    .hover(position.position)
+            }),
+            _ => Response::new_err(
+                request.id,
+                ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request \"{}\"", request.method),
+            ),
+        }
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<(), ServeError> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: <DidOpenTextDocument as NotificationType>::Params =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                let document = Document::new(&uri, params.text_document.text, None);
+                self.documents.insert(uri.clone(), document);
+                self.publish_diagnostics(uri)
+            }
+            DidChangeTextDocument::METHOD => {
+                let mut params: <DidChangeTextDocument as NotificationType>::Params =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                // Only full syncs are asked for, so the last change has the whole text
+                let text = match params.content_changes.pop() {
+                    Some(change) => change.text,
+                    None => return Ok(()),
+                };
+                let last_parsed = self
+                    .documents
+                    .remove(&uri)
+                    .and_then(|document| document.script_file);
+                let document = Document::new(&uri, text, last_parsed);
+                self.documents.insert(uri.clone(), document);
+                self.publish_diagnostics(uri)
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: <DidCloseTextDocument as NotificationType>::Params =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                self.publish_diagnostics(uri)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Closed documents get their diagnostics cleared
+     */
+    fn publish_diagnostics(&self, uri: Url) -> Result<(), ServeError> {
+        let diagnostics = self
+            .documents
+            .get(&uri)
+            .map(Document::lsp_diagnostics)
+            .unwrap_or_default();
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_string(),
+                params,
+            )))?;
+        Ok(())
+    }
+}
+
+/**
+ * Runs a language server over stdin and stdout until the client asks it to exit
+ */
+pub fn serve() -> Result<(), ServeError> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![":".to_string(), " ".to_string()]),
+            ..Default::default()
+        }),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server {
+        connection,
+        documents: HashMap::new(),
+    };
+    while let Ok(message) = server.connection.receiver.recv() {
+        match message {
+            Message::Request(request) => {
+                if server.connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.handle_request(request);
+                server.connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => server.handle_notification(notification)?,
+            Message::Response(_) => {}
+        }
+    }
+    // The connection has to be dropped first so that the thread writing to stdout finishes
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_task_values() {
+        assert!(completes_task("  task: bu"));
+        assert!(completes_task("  - task: \"bu"));
+        assert!(completes_task("default = 'bu"));
+        assert!(!completes_task("  script: bu"));
+        assert!(!completes_task("  subtask: bu"));
+        // Whitespace that takes more than one byte
+        assert!(completes_task("task:\u{a0}bu"));
+        assert!(completes_task("task:\u{3000}"));
+        assert!(!completes_task("script:\u{a0}bu"));
+    }
+}
@@ -2,7 +2,7 @@ use clap::Command;
 
 use std::collections::VecDeque;
 
-use std::fs;
+use std::io::IsTerminal;
 
 use std::path::Path;
 
@@ -10,24 +10,124 @@ use std::sync::Arc;
 
 use std::process::{exit, ExitStatus};
 
-use scriptplan_bash::scriptplan_core::ScriptParser;
-use scriptplan_bash::yaml_rust::YamlLoader;
-use scriptplan_bash::YamlScriptParser;
+use scriptplan_bash::scriptplan_core::{
+    format_plan, is_task_pattern, Diagnostic, MultiReporter, NodePath, Platform, Reporter,
+    RunContext, RunEvent, ScriptParser, TerminalReporter,
+};
+use scriptplan_bash::{check, LoadError, PlanCache, ScriptFile, ScriptFileParser, Source};
+use scriptplan_nu::NuCommand;
 use std::convert::TryFrom;
 
+mod junit;
+mod lsp;
+mod picker;
+mod profile;
+mod reporter;
+mod select;
+mod tree;
+use junit::JUnitReporter;
+use profile::ProfileReporter;
+use reporter::JsonReporter;
+
 use ansi_term::{
     Colour::{Cyan, Purple},
     Style,
 };
 
+const BUILTIN_COMMANDS: [&str; 3] = ["check", "lsp", "run"];
+
+/**
+ * Everything `check` reports, plus tasks that a builtin command takes the name of. Those can still be run with
+ * `scriptplan run <name>` or through other tasks.
+ */
+fn check_with_builtins(script_file: &ScriptFile, parser: &ScriptFileParser) -> Vec<Diagnostic> {
+    let mut diagnostics = check(script_file, parser);
+    for (task, info) in parser.visible_tasks() {
+        let path = NodePath::task(task);
+        if BUILTIN_COMMANDS.contains(&task.as_str()) {
+            let message = format!(
+                "\"{}\" is a builtin command, so this task can only be run with `scriptplan run {}` or through other tasks",
+                task, task
+            );
+            diagnostics.push(parser.locate(Diagnostic::new(path.clone(), message).about_key()));
+        }
+        for alias in info
+            .aliases
+            .iter()
+            .filter(|alias| BUILTIN_COMMANDS.contains(&alias.as_str()))
+        {
+            let message = format!(
+                "\"{}\" is a builtin command, so this alias only works with `scriptplan run {}`",
+                alias, alias
+            );
+            diagnostics.push(parser.locate(Diagnostic::new(path.key("aliases"), message)));
+        }
+    }
+    diagnostics
+}
+
 fn new_cli_app<'a>(name: &'a str) -> Command<'a> {
-    Command::new(name).arg(
-        clap::Arg::new("script-file")
-            .short('s')
-            .long("script-file")
-            .takes_value(true)
-            .default_value("./default.scripts.yaml"),
-    )
+    Command::new(name)
+        .arg(
+            clap::Arg::new("script-file")
+                .short('s')
+                .long("script-file")
+                .takes_value(true)
+                .default_value("./default.scripts.yaml"),
+        )
+        .arg(
+            clap::Arg::new("dry-run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Prints what the task would run without running it"),
+        )
+        .arg(
+            clap::Arg::new("platform")
+                .long("platform")
+                .takes_value(true)
+                .possible_values(Platform::ALL.map(|platform| platform.as_str()))
+                .help("Plans the task for another platform. Implies --dry-run if it's not the current platform"),
+        )
+        .arg(
+            clap::Arg::new("trace")
+                .long("trace")
+                .takes_value(false)
+                .help("Prints each shell command as it's run, prefixed with its task"),
+        )
+        .arg(
+            clap::Arg::new("tree")
+                .long("tree")
+                .takes_value(false)
+                .help("Lists the tasks grouped by the dotted namespaces in their names"),
+        )
+        .arg(
+            clap::Arg::new("no-default")
+                .long("no-default")
+                .takes_value(false)
+                .help("Prints the usage instead of running the script file's default task when no task is given"),
+        )
+        .arg(
+            clap::Arg::new("reporter")
+                .long("reporter")
+                .takes_value(true)
+                .possible_values(["terminal", "json"])
+                .default_value("terminal")
+                .help("json prints newline delimited JSON events instead of the tasks' output"),
+        )
+        .arg(
+            clap::Arg::new("profile-out")
+                .long("profile-out")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes a Chrome trace of the run that can be opened in Perfetto or about:tracing"),
+        )
+        .arg(
+            clap::Arg::new("junit")
+                .long("junit")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes a JUnit XML report with a testcase for each command that was run"),
+        )
 }
 
 #[tokio::main]
@@ -49,78 +149,349 @@ async fn main() {
         )
         .get_matches();
 
+    // Editors start the language server for whichever files they open, so it doesn't need a script file
+    if let Some(("lsp", _)) = initial_matches.subcommand() {
+        if let Err(err) = lsp::serve() {
+            eprintln!("The language server stopped unexpectedly. {}", err);
+            exit(1);
+        }
+        return;
+    }
+
     let script_file = initial_matches.value_of("script-file").unwrap();
 
     let path = Path::new(script_file);
 
-    let script_file_result = fs::read_to_string(path);
+    let source = match Source::read(path) {
+        Ok(source) => Arc::new(source),
+        Err(LoadError::Read(_)) => {
+            println!("Could not find script file \"{}\". Make sure the file exists and this program has permission to read it.", file_style.paint(script_file));
+            return;
+        }
+        Err(err) => {
+            println!(
+                "Unable to parse the script file \"{}\". {}",
+                file_style.paint(script_file),
+                err
+            );
+            return;
+        }
+    };
 
-    if let Ok(s) = script_file_result {
-        let docs_result = YamlLoader::load_from_str(&s);
+    let platform = initial_matches
+        .value_of("platform")
+        .map(|name| name.parse::<Platform>().unwrap());
+    let dry_run = initial_matches.is_present("dry-run")
+        || platform.is_some_and(|platform| Some(platform) != Platform::current());
+    let planned_platform = platform.or_else(Platform::current);
 
-        if let Ok(mut docs) = docs_result {
-            let doc = docs.remove(0);
+    // Plans only get parsed again when the file changes
+    let cache = PlanCache::new(PlanCache::default_dir());
+    let cached_plan = cache.load(&source, planned_platform);
+    let is_cached = cached_plan.is_some();
+    let scriptplan = match cached_plan {
+        Some(plan) => ScriptFileParser::from(plan),
+        None => {
+            let loaded_file = parse_script_file(&source, script_file);
+            match ScriptFileParser::try_from(&loaded_file) {
+                Ok(scriptplan) => scriptplan,
+                Err(diagnostic) => {
+                    println!("{}", source.render(&diagnostic));
+                    exit(1);
+                }
+            }
+        }
+    };
+    let mut scriptplan = scriptplan.with_source(source.clone());
+    register_backends(&mut scriptplan);
+    // Platform variants get picked while compiling, so this has to be set before the plan is cached
+    if platform.is_some() {
+        scriptplan.options.platform = platform;
+    }
+    scriptplan.options.trace = initial_matches.is_present("trace");
+    if !is_cached {
+        // Files with broken tasks don't get cached so that the problems are still reported when they're run
+        if let Ok(plan) = scriptplan.compile() {
+            let _ = cache.store(&source, planned_platform, plan);
+        }
+    }
 
-            let map = doc.into_hash().unwrap();
+    if initial_matches.is_present("tree") {
+        print!("{}", tree::render_tree(&scriptplan));
+        return;
+    }
 
-            let scriptplan = YamlScriptParser::try_from(&map).unwrap();
+    // Parallel tasks get spawned onto the runtime so they share the parser
+    let scriptplan = Arc::new(scriptplan);
+    let new_app_name = format!("Scriptplan CLI (using \"{}\")", script_file);
 
-            let new_app_name = format!("Scriptplan CLI (using \"{}\")", script_file);
+    let base_app = new_cli_app(new_app_name.as_str())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .disable_version_flag(true)
+        .disable_help_subcommand(true)
+        .subcommand(Command::new("check").about("Checks every task for problems"))
+        .subcommand(
+            Command::new("lsp").about("Starts a language server for script files that talks over stdio"),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Runs several tasks one after the other, stopping at the first failure")
+                .trailing_var_arg(true)
+                .disable_help_subcommand(true)
+                .arg(
+                    clap::Arg::new("parallel")
+                        .short('p')
+                        .long("parallel")
+                        .takes_value(false)
+                        .help("Runs the tasks at the same time instead"),
+                )
+                .arg(
+                    clap::Arg::new("TASKS")
+                        .required(true)
+                        .multiple_values(true)
+                        .use_value_delimiter(false)
+                        .help("Task names or patterns like format.* and install.**, then a -- before each one's arguments in turn (E.g. lint test -- --fix -- --nocapture)"),
+                ),
+        );
 
-            let app = scriptplan.tasks.keys().fold(
-                new_cli_app(new_app_name.as_str())
-                    .subcommand_required(true)
-                    .arg_required_else_help(true)
+    let app = scriptplan
+        .visible_tasks()
+        // Builtin commands take precedence over tasks with the same name, which `check` reports and
+        // `scriptplan run <name>` still reaches
+        .filter(|(task, _)| !BUILTIN_COMMANDS.contains(&task.as_str()))
+        .fold(base_app, |temp_app, (task, info)| {
+            temp_app.subcommand(
+                Command::new(task.as_str())
+                    .about(info.description.as_deref().unwrap_or_default())
+                    .visible_aliases(&info.aliases.iter().map(String::as_str).collect::<Vec<_>>())
+                    .trailing_var_arg(true)
+                    .disable_help_flag(true)
+                    .disable_help_subcommand(true)
                     .disable_version_flag(true)
-                    .disable_help_subcommand(true),
-                |temp_app, task| {
-                    temp_app.subcommand(
-                        Command::new(task.to_string())
-                            .trailing_var_arg(true)
-                            .disable_help_flag(true)
-                            .disable_help_subcommand(true)
-                            .disable_version_flag(true)
+                    .allow_hyphen_values(true)
+                    .arg(
+                        clap::Arg::new("EXTRA_ARGUMENTS")
+                            .multiple_values(true)
                             .allow_hyphen_values(true)
-                            .arg(
-                                clap::Arg::new("EXTRA_ARGUMENTS")
-                                    .multiple_values(true)
-                                    .allow_hyphen_values(true)
-                                    .use_value_delimiter(false),
-                            ),
-                    )
-                },
-            );
+                            .use_value_delimiter(false),
+                    ),
+            )
+        });
 
-            let app_matches = app.get_matches();
+    let no_task_given =
+        initial_matches.subcommand().is_none() && !initial_matches.is_present("help");
+    let default_task = scriptplan
+        .default_task()
+        .filter(|_| !initial_matches.is_present("no-default"));
+    // With no task given, interactive users get to pick one instead of reading the usage
+    let app_matches = if no_task_given
+        && std::io::stdout().is_terminal()
+        && app
+            .get_subcommands()
+            .any(|task| !BUILTIN_COMMANDS.contains(&task.get_name()))
+    {
+        match picker::pick_task(&scriptplan) {
+            Some(picked) => app.get_matches_from(std::env::args().chain(picked)),
+            None => exit(130),
+        }
+    } else if let Some(default_task) = default_task.filter(|_| no_task_given) {
+        match default_task {
+            Ok(task) => app.get_matches_from(std::env::args().chain([task.to_string()])),
+            Err(diagnostic) => {
+                println!("{}", scriptplan.render(&diagnostic));
+                exit(1);
+            }
+        }
+    } else if let Some(index) = pattern_index(&initial_matches) {
+        // Patterns aren't subcommands themselves, so they get selected the same way `run` does it
+        let mut args: Vec<String> = std::env::args().collect();
+        args.insert(index, "run".to_string());
+        app.get_matches_from(args)
+    } else {
+        app.get_matches()
+    };
 
-            let task_subcommand = app_matches.subcommand();
+    let task_subcommand = app_matches.subcommand();
 
-            if let Some((name, root_task)) = task_subcommand {
-                let user_vars_iter: VecDeque<_> = (|| {
-                    if let Some(values) = root_task.values_of("EXTRA_ARGUMENTS") {
-                        return values.map(|x| Arc::new(x.to_string())).collect();
-                    } else {
-                        return VecDeque::new().into_iter().collect();
-                    }
-                })();
+    if let Some(("check", _)) = task_subcommand {
+        let loaded_file = parse_script_file(&source, script_file);
+        let diagnostics = check_with_builtins(&loaded_file, &scriptplan);
+        if diagnostics.is_empty() {
+            println!("No problems found in \"{}\"", file_style.paint(script_file));
+            return;
+        }
+        for diagnostic in diagnostics.iter() {
+            println!("{}\n", source.render(diagnostic));
+        }
+        exit(1);
+    }
 
-                let status = scriptplan
-                .parse(name)
-                .expect(format!("\"{}\" was a valid YAML file but Script plan still wasn't able to parse the \"{}\" task. Possible a bug.", file_style.paint(script_file), task_style.paint(name)).as_str())
-                .run(&scriptplan, user_vars_iter)
-                .await
-                .expect(format!("Tried to execute the task \"{}\" but it unexpectedly failed", task_style.paint(name)).as_str());
+    if let Some((name, root_task)) = task_subcommand {
+        let parse_task = |task: &str| match scriptplan.parse(task) {
+            Ok(script) => script,
+            Err(diagnostic) => {
+                println!(
+                    "Unable to parse the \"{}\" task.\n{}",
+                    task_style.paint(task),
+                    scriptplan.render(&diagnostic)
+                );
+                exit(1);
+            }
+        };
 
-                exit_with_status(status);
+        let (script, user_vars_iter) = if name == "run" {
+            let values: Vec<&str> = root_task.values_of("TASKS").unwrap().collect();
+            let aliases = match select::split_task_args(&values, &scriptplan) {
+                Ok(aliases) => aliases,
+                Err(message) => {
+                    println!("{}", message);
+                    exit(1);
+                }
+            };
+            // Catch typos before any of the tasks get run
+            for alias in aliases.iter() {
+                parse_task(&alias.task);
             }
+            let parallel = root_task.is_present("parallel");
+            (
+                Arc::new(select::combine(aliases, parallel)),
+                VecDeque::new(),
+            )
         } else {
+            let user_vars_iter: VecDeque<_> = root_task
+                .values_of("EXTRA_ARGUMENTS")
+                .map(|values| values.map(|x| Arc::new(x.to_string())).collect())
+                .unwrap_or_default();
+            (parse_task(name), user_vars_iter)
+        };
+
+        if dry_run {
+            print!(
+                "{}",
+                format_plan(
+                    &script,
+                    &*scriptplan,
+                    &RunContext::new(name).with_platform(platform),
+                )
+            );
+            return;
+        }
+
+        let mut reporters: Vec<Arc<dyn Reporter>> = match initial_matches.value_of("reporter") {
+            Some("json") => vec![Arc::new(JsonReporter)],
+            _ => vec![Arc::new(TerminalReporter)],
+        };
+        let profile_out = initial_matches.value_of("profile-out").map(Path::new);
+        let profile = Arc::new(ProfileReporter::default());
+        if profile_out.is_some() {
+            reporters.push(profile.clone());
+        }
+        let junit_out = initial_matches.value_of("junit").map(Path::new);
+        let junit = Arc::new(JUnitReporter::default());
+        if junit_out.is_some() {
+            reporters.push(junit.clone());
+        }
+        let reporter = Arc::new(MultiReporter(reporters));
+        let vars = match scriptplan.vars() {
+            Ok(vars) => vars,
+            Err(diagnostic) => {
+                println!("{}", source.render(&diagnostic));
+                exit(1);
+            }
+        };
+        let context = RunContext::new(name)
+            .with_platform(platform)
+            .with_reporter(reporter)
+            .with_vars(vars);
+        context.report(RunEvent::PlanResolved {
+            task: name.to_string(),
+            plan: format_plan(&script, &*scriptplan, &context),
+        });
+
+        let result = script
+            .run_as_task(&scriptplan, &context, user_vars_iter)
+            .await;
+
+        if let Some(profile_out) = profile_out {
+            report_write_error("profile", profile_out, profile.write(profile_out));
+        }
+        if let Some(junit_out) = junit_out {
+            report_write_error("JUnit report", junit_out, junit.write(junit_out));
+        }
+
+        match result {
+            Ok(status) => exit_with_status(status),
+            Err(()) => {
+                // Whatever went wrong has already been printed
+                eprintln!(
+                    "Tried to execute the task \"{}\" but it unexpectedly failed",
+                    task_style.paint(name)
+                );
+                exit(1);
+            }
+        }
+    }
+}
+
+/**
+ * Where the task pattern given in place of a subcommand (like `scriptplan 'format.*'`) sits in the arguments
+ */
+fn pattern_index(matches: &clap::ArgMatches) -> Option<usize> {
+    let (name, sub_matches) = matches.subcommand()?;
+    if !is_task_pattern(name) {
+        return None;
+    }
+    let trailing = sub_matches.values_of("").map_or(0, |values| values.count());
+    Some(std::env::args().count() - 1 - trailing)
+}
+
+/**
+ * Cached plans don't keep the file around, so anything that needs it (like `check`) parses it here
+ */
+fn parse_script_file(source: &Source, script_file: &str) -> ScriptFile {
+    match source.parse() {
+        Ok(loaded_file) => loaded_file,
+        Err(LoadError::Parse(diagnostic)) => {
             println!(
-                "Unable to parse the script file \"{}\". Make sure the file contains valid YAML.",
-                file_style.paint(script_file)
+                "Unable to parse the script file \"{}\".\n{}",
+                Style::new().fg(Purple).paint(script_file),
+                source.render(&diagnostic)
             );
+            exit(1);
         }
-    } else {
-        println!("Could not find script file \"{}\". Make sure the file exists and this program has permission to read it.", file_style.paint(script_file));
+        Err(err) => {
+            println!(
+                "Unable to parse the script file \"{}\". {}",
+                Style::new().fg(Purple).paint(script_file),
+                err
+            );
+            exit(1);
+        }
+    }
+}
+/**
+ * Backends that live outside of scriptplan-bash
+ */
+fn register_backends(scriptplan: &mut ScriptFileParser) {
+    scriptplan.register_backend("nu", |source, options| {
+        Box::new(NuCommand {
+            source: source.to_string(),
+            stdin: options.stdin.clone(),
+            env: options.env.clone(),
+            cwd: options.cwd.clone(),
+        })
+    });
+}
+
+fn report_write_error(what: &str, path: &Path, result: std::io::Result<()>) {
+    if let Err(err) = result {
+        eprintln!(
+            "Unable to write the {} to \"{}\". {}",
+            what,
+            Style::new().fg(Purple).paint(path.display().to_string()),
+            err
+        );
     }
 }
 
@@ -0,0 +1,46 @@
+use dialoguer::{theme::ColorfulTheme, FuzzySelect, Input};
+
+use scriptplan_bash::scriptplan_core::required_args;
+use scriptplan_bash::{ScriptFileParser, TaskInfo};
+
+use crate::BUILTIN_COMMANDS;
+
+/**
+ * Asks which task to run, then for each of the positional arguments it references.
+ * Returns the task's name followed by its arguments, or nothing if the user backed out.
+ */
+pub fn pick_task(parser: &ScriptFileParser) -> Option<Vec<String>> {
+    let tasks: Vec<(&String, &TaskInfo)> = parser
+        .visible_tasks()
+        .filter(|(name, _)| !BUILTIN_COMMANDS.contains(&name.as_str()))
+        .collect();
+
+    let width = tasks.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let items: Vec<String> = tasks
+        .iter()
+        .map(|(name, info)| match &info.description {
+            Some(description) => format!("{:width$}  {}", name, description, width = width),
+            None => name.to_string(),
+        })
+        .collect();
+
+    let theme = ColorfulTheme::default();
+    let chosen = FuzzySelect::with_theme(&theme)
+        .with_prompt("Task to run")
+        .items(&items)
+        .default(0)
+        .interact_opt()
+        .ok()??;
+    let name = tasks[chosen].0;
+
+    let mut picked = vec![name.to_string()];
+    for position in 1..=required_args(name, parser) {
+        let arg: String = Input::with_theme(&theme)
+            .with_prompt(format!("${}", position))
+            .allow_empty(true)
+            .interact_text()
+            .ok()?;
+        picked.push(arg);
+    }
+    Some(picked)
+}
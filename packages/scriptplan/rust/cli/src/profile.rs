@@ -0,0 +1,169 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use scriptplan_bash::scriptplan_core::{Reporter, RunContext, RunEvent};
+use serde_json::{json, Value};
+
+/**
+ * Records when everything starts and finishes so it can be written out in the Chrome trace event format,
+ * which Perfetto and about:tracing can open. Each lane of a run becomes a thread in the trace.
+ */
+pub struct ProfileReporter {
+    started: Instant,
+    events: Mutex<Vec<Value>>,
+}
+
+impl Default for ProfileReporter {
+    fn default() -> Self {
+        ProfileReporter {
+            started: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl ProfileReporter {
+    pub fn trace(&self) -> Value {
+        let mut events = self.events.lock().unwrap().clone();
+        let lanes: BTreeSet<u64> = events
+            .iter()
+            .filter_map(|event| event["tid"].as_u64())
+            .collect();
+        for lane in lanes {
+            let name = if lane == 0 {
+                "main".to_string()
+            } else {
+                format!("parallel branch {}", lane)
+            };
+            events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": lane,
+                "args": { "name": name },
+            }));
+        }
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(&self.trace())?)
+    }
+}
+
+impl Reporter for ProfileReporter {
+    fn report(&self, context: &RunContext, event: RunEvent) {
+        // Begin and end events only need to be nested within a lane, which they always are since lanes run in series
+        let (phase, name, category, args) = match event {
+            RunEvent::TaskStarted { task } => ("B", task, "task", json!({})),
+            RunEvent::TaskFinished { task, status, .. } => {
+                ("E", task, "task", json!({ "status": status }))
+            }
+            RunEvent::GroupStarted { kind, task, .. } => {
+                ("B", kind.to_string(), "group", json!({ "task": task }))
+            }
+            RunEvent::GroupFinished { status, .. } => {
+                ("E", String::new(), "group", json!({ "status": status }))
+            }
+            RunEvent::CommandStarted { command, task, .. } => {
+                ("B", command, "command", json!({ "task": task }))
+            }
+            RunEvent::CommandFinished { status, .. } => {
+                ("E", String::new(), "command", json!({ "status": status }))
+            }
+            RunEvent::Skipped { task, condition } => (
+                "i",
+                format!("skipped: {}", condition),
+                "skip",
+                json!({ "task": task }),
+            ),
+            RunEvent::PlanResolved { .. } | RunEvent::Output { .. } => return,
+        };
+
+        let mut event = json!({
+            "name": name,
+            "cat": category,
+            "ph": phase,
+            "ts": self.started.elapsed().as_secs_f64() * 1_000_000.0,
+            "pid": 1,
+            "tid": context.lane,
+            "args": args,
+        });
+        if phase == "i" {
+            event["s"] = "t".into();
+        }
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scriptplan_bash::scriptplan_core::{alias, parallel, series, Plan};
+    use scriptplan_bash::BashCommand;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::Arc;
+
+    #[test]
+    fn nests_events_within_each_lane() {
+        let plan = Plan::new()
+            .task("lint", BashCommand::from("true lint"))
+            .task(
+                "ci",
+                series![
+                    parallel![alias("lint"), BashCommand::from("true test")],
+                    BashCommand::from("true build"),
+                ],
+            );
+        let profile = Arc::new(ProfileReporter::default());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let status = runtime
+            .block_on(plan.run_with_reporter("ci", VecDeque::new(), profile.clone()))
+            .unwrap();
+        assert!(status.success());
+
+        let trace = profile.trace();
+        let mut stacks: BTreeMap<u64, Vec<(String, String)>> = BTreeMap::new();
+        let mut command_lanes = BTreeMap::new();
+        let mut lane_names = BTreeMap::new();
+        for event in trace["traceEvents"].as_array().unwrap() {
+            let tid = event["tid"].as_u64().unwrap();
+            let name = event["name"].as_str().unwrap().to_string();
+            let category = event["cat"].as_str().unwrap_or_default().to_string();
+            let stack = stacks.entry(tid).or_default();
+            match event["ph"].as_str().unwrap() {
+                "B" => {
+                    if category == "command" {
+                        command_lanes.insert(name.clone(), tid);
+                    }
+                    stack.push((category, name));
+                }
+                "E" => {
+                    let (begun, begun_name) =
+                        stack.pop().expect("Ended something that never began");
+                    assert_eq!(begun, category, "{} ended as a {}", begun_name, category);
+                }
+                "M" => {
+                    lane_names.insert(tid, event["args"]["name"].as_str().unwrap().to_string());
+                }
+                phase => panic!("Unexpected {} event", phase),
+            }
+        }
+
+        assert!(stacks.values().all(Vec::is_empty), "{:?}", stacks);
+        // The first branch carries on in the group's lane while the others get lanes of their own
+        assert_eq!(command_lanes["true lint"], 0);
+        assert_eq!(command_lanes["true test"], 1);
+        assert_eq!(command_lanes["true build"], 0);
+        assert_eq!(lane_names[&0], "main");
+        assert_eq!(lane_names[&1], "parallel branch 1");
+        assert_eq!(lane_names.len(), 2);
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use scriptplan_bash::scriptplan_core::{Reporter, RunContext, RunEvent};
+use serde_json::{json, Value};
+
+pub fn millis(duration: &Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/**
+ * Prints each event as a line of JSON to stdout. Command output is captured so stdout only ever contains events.
+ */
+#[derive(Debug, Default)]
+pub struct JsonReporter;
+
+impl JsonReporter {
+    fn to_json(event: &RunEvent) -> Value {
+        match event {
+            RunEvent::PlanResolved { task, plan } => {
+                json!({ "event": "plan_resolved", "task": task, "plan": plan })
+            }
+            RunEvent::TaskStarted { task } => json!({ "event": "task_started", "task": task }),
+            RunEvent::TaskFinished {
+                task,
+                status,
+                duration,
+            } => json!({
+                "event": "task_finished",
+                "task": task,
+                "status": status,
+                "duration_ms": millis(duration),
+            }),
+            RunEvent::GroupStarted { id, task, kind } => json!({
+                "event": "group_started",
+                "id": id,
+                "task": task,
+                "kind": kind,
+            }),
+            RunEvent::GroupFinished {
+                id,
+                status,
+                duration,
+            } => json!({
+                "event": "group_finished",
+                "id": id,
+                "status": status,
+                "duration_ms": millis(duration),
+            }),
+            RunEvent::CommandStarted { id, task, command } => json!({
+                "event": "command_started",
+                "id": id,
+                "task": task,
+                "command": command,
+            }),
+            RunEvent::Output { id, stream, chunk } => json!({
+                "event": "output",
+                "id": id,
+                "stream": stream.as_str(),
+                "chunk": chunk,
+            }),
+            RunEvent::CommandFinished {
+                id,
+                status,
+                duration,
+            } => json!({
+                "event": "command_finished",
+                "id": id,
+                "status": status,
+                "duration_ms": millis(duration),
+            }),
+            RunEvent::Skipped { task, condition } => {
+                json!({ "event": "skipped", "task": task, "condition": condition })
+            }
+        }
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn report(&self, context: &RunContext, event: RunEvent) {
+        let mut json = JsonReporter::to_json(&event);
+        json["lane"] = context.lane.into();
+        // println locks stdout for the whole line so events from parallel scripts never interleave
+        println!("{}", json);
+    }
+
+    fn captures_output(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use scriptplan_bash::scriptplan_core::{
+    is_task_pattern, match_tasks, Alias, Command, CommandGroup, Script, ScriptGroup,
+};
+use scriptplan_bash::ScriptFileParser;
+
+/**
+ * Splits `scriptplan run`'s values into the tasks to run and their arguments.
+ * Task names come first, then each `--` starts the arguments for the next task in turn:
+ * `lint test -- --fix -- --nocapture` runs `lint --fix` then `test --nocapture`.
+ * Patterns like `format.*` expand to every matching task, which all get the pattern's arguments.
+ * Hidden tasks can't be selected.
+ */
+pub fn split_task_args(values: &[&str], parser: &ScriptFileParser) -> Result<Vec<Alias>, String> {
+    let mut segments = values.split(|value| *value == "--");
+    let tasks = segments.next().unwrap_or_default();
+    let arg_lists: Vec<&[&str]> = segments.collect();
+    if arg_lists.len() > tasks.len() {
+        return Err(format!(
+            "Got arguments for {} tasks but only {} were given",
+            arg_lists.len(),
+            tasks.len()
+        ));
+    }
+
+    let mut aliases: Vec<Alias> = Vec::new();
+    for (i, task) in tasks.iter().enumerate() {
+        let args = arg_lists.get(i).copied().unwrap_or_default();
+        let matched = if is_task_pattern(task) {
+            let task_names = parser.visible_tasks().map(|(name, _)| name.as_str());
+            let matched = match_tasks(task, task_names);
+            if matched.is_empty() {
+                return Err(format!("No tasks match \"{}\"", task));
+            }
+            matched
+        } else if let Some(found) = parser.find_visible_task(task) {
+            vec![found]
+        } else if parser.tasks.contains_key(*task) {
+            return Err(format!(
+                "The task \"{}\" is hidden so it can only be run through other tasks",
+                task
+            ));
+        } else {
+            // Parsing it reports that it doesn't exist along with what might have been meant
+            vec![*task]
+        };
+        for task in matched {
+            let alias = Alias::new(task, args.iter().copied());
+            // Asking for the same thing twice only runs it once
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+    }
+    Ok(aliases)
+}
+
+/**
+ * Combines the selected tasks into a single group so that they share reporting and output handling.
+ * Like `&&` in a shell, the series stops at the first task that fails.
+ */
+pub fn combine<CommandGeneric: Command>(
+    aliases: Vec<Alias>,
+    parallel: bool,
+) -> Script<CommandGeneric> {
+    let mut scripts = aliases.into_iter().map(Script::Alias);
+    let first = scripts
+        .next()
+        .expect("At least one task is always selected");
+    let group = ScriptGroup {
+        bail: !parallel,
+        first: Arc::new(first),
+        rest: scripts.map(Arc::new).collect(),
+    };
+    Script::Group(Box::new(if parallel {
+        CommandGroup::Parallel(group)
+    } else {
+        CommandGroup::Series(group)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scriptplan_bash::{Format, ScriptFile};
+    use std::convert::TryFrom;
+
+    const SCRIPT_FILE: &str = "
+format.rust: cargo fmt
+format.js: prettier --write .
+lint: cargo clippy
+test: cargo test
+_setup: echo setup
+";
+
+    fn split(values: &[&str]) -> Result<Vec<(String, Vec<String>)>, String> {
+        let script_file = ScriptFile::parse_str(SCRIPT_FILE, Format::Yaml).unwrap();
+        let parser = ScriptFileParser::try_from(&script_file).unwrap();
+        let aliases = split_task_args(values, &parser)?;
+        Ok(aliases
+            .into_iter()
+            .map(|alias| {
+                let args = alias.args.iter().map(|arg| arg.to_string()).collect();
+                (alias.task, args)
+            })
+            .collect())
+    }
+
+    fn selected(task: &str, args: &[&str]) -> (String, Vec<String>) {
+        (
+            task.to_string(),
+            args.iter().map(|arg| arg.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn gives_each_task_its_own_arguments() {
+        assert_eq!(
+            split(&["lint", "test", "--", "--fix", "--", "--nocapture", "-q"]),
+            Ok(vec![
+                selected("lint", &["--fix"]),
+                selected("test", &["--nocapture", "-q"]),
+            ])
+        );
+        assert_eq!(
+            split(&["lint", "test", "--", "--fix"]),
+            Ok(vec![selected("lint", &["--fix"]), selected("test", &[])])
+        );
+        assert_eq!(
+            split(&["lint", "--", "--fix", "--", "--nocapture"]),
+            Err("Got arguments for 2 tasks but only 1 were given".to_string())
+        );
+    }
+
+    #[test]
+    fn expands_patterns_with_their_arguments() {
+        assert_eq!(
+            split(&["format.*", "--", "--check"]),
+            Ok(vec![
+                selected("format.js", &["--check"]),
+                selected("format.rust", &["--check"]),
+            ])
+        );
+        assert_eq!(
+            split(&["deploy.*"]),
+            Err("No tasks match \"deploy.*\"".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_hidden_tasks() {
+        assert_eq!(
+            split(&["_setup"]),
+            Err(
+                "The task \"_setup\" is hidden so it can only be run through other tasks"
+                    .to_string()
+            )
+        );
+        // Patterns skip over them instead
+        assert_eq!(split(&["_*"]), Err("No tasks match \"_*\"".to_string()));
+    }
+
+    #[test]
+    fn runs_repeated_selections_once() {
+        assert_eq!(
+            split(&["format.rust", "format.*", "format.rust"]),
+            Ok(vec![
+                selected("format.rust", &[]),
+                selected("format.js", &[])
+            ])
+        );
+        // Different arguments make it a different run
+        assert_eq!(
+            split(&["lint", "lint", "--", "--fix"]),
+            Ok(vec![selected("lint", &["--fix"]), selected("lint", &[])])
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_tasks_for_parsing_to_report() {
+        assert_eq!(split(&["lnt"]), Ok(vec![selected("lnt", &[])]));
+    }
+}
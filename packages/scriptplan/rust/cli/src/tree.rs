@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use ansi_term::{Colour::Cyan, Style};
+
+use scriptplan_bash::ScriptFileParser;
+
+use crate::BUILTIN_COMMANDS;
+
+/**
+ * A segment of a dotted task name. It's only a task itself if something is defined with exactly that name.
+ */
+#[derive(Default)]
+struct Namespace<'a> {
+    /// The task's description, if it's a task
+    task: Option<Option<&'a str>>,
+    children: BTreeMap<&'a str, Namespace<'a>>,
+}
+
+impl<'a> Namespace<'a> {
+    fn insert(&mut self, segments: &[&'a str], description: Option<&'a str>) {
+        match segments.split_first() {
+            None => self.task = Some(description),
+            Some((segment, rest)) => self
+                .children
+                .entry(segment)
+                .or_default()
+                .insert(rest, description),
+        }
+    }
+
+    fn render_line(&self, segment: &str, lead: &str, out: &mut String) {
+        out.push_str(lead);
+        match self.task {
+            Some(description) => {
+                out.push_str(&Style::new().fg(Cyan).paint(segment).to_string());
+                if let Some(description) = description {
+                    out.push_str(&format!("  {}", Style::new().dimmed().paint(description)));
+                }
+            }
+            None => out.push_str(segment),
+        }
+        out.push('\n');
+    }
+
+    fn render_children(&self, prefix: &str, out: &mut String) {
+        for (i, (segment, child)) in self.children.iter().enumerate() {
+            let (branch, indent) = if i == self.children.len() - 1 {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            child.render_line(segment, &format!("{}{}", prefix, branch), out);
+            child.render_children(&format!("{}{}", prefix, indent), out);
+        }
+    }
+}
+
+/**
+ * Lists every task grouped by the dotted namespaces in their names
+ */
+pub fn render_tree(parser: &ScriptFileParser) -> String {
+    let mut root = Namespace::default();
+    for (name, info) in parser.visible_tasks() {
+        if BUILTIN_COMMANDS.contains(&name.as_str()) {
+            continue;
+        }
+        let segments: Vec<&str> = name.split('.').collect();
+        root.insert(&segments, info.description.as_deref());
+    }
+
+    let mut out = String::new();
+    // Top level namespaces don't hang off anything
+    for (segment, child) in root.children.iter() {
+        child.render_line(segment, "", &mut out);
+        child.render_children("", &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scriptplan_bash::{Format, ScriptFile};
+    use std::convert::TryFrom;
+
+    fn render(yaml_str: &str) -> String {
+        let script_file = ScriptFile::parse_str(yaml_str, Format::Yaml).unwrap();
+        let parser = ScriptFileParser::try_from(&script_file).unwrap();
+        render_tree(&parser)
+    }
+
+    fn task(name: &str) -> String {
+        Style::new().fg(Cyan).paint(name).to_string()
+    }
+
+    #[test]
+    fn nests_tasks_under_their_namespaces() {
+        let rendered = render(
+            "
+build: cargo build
+format.rust: cargo fmt
+format.js.app: prettier app
+format.js.lib: prettier lib
+install.deps:
+  description: Installs everything
+  script: cargo fetch
+_setup: echo setup
+",
+        );
+        let expected = [
+            task("build"),
+            "format".to_string(),
+            "├── js".to_string(),
+            format!("│   ├── {}", task("app")),
+            format!("│   └── {}", task("lib")),
+            format!("└── {}", task("rust")),
+            "install".to_string(),
+            format!(
+                "└── {}  {}",
+                task("deps"),
+                Style::new().dimmed().paint("Installs everything")
+            ),
+        ];
+        assert_eq!(rendered, format!("{}\n", expected.join("\n")));
+    }
+
+    #[test]
+    fn keeps_namespaces_that_are_tasks_themselves() {
+        let rendered =
+            render("check: echo builtin\ntest: cargo test\ntest.unit: cargo test --lib\n");
+        assert_eq!(
+            rendered,
+            format!("{}\n└── {}\n", task("test"), task("unit"))
+        );
+    }
+}
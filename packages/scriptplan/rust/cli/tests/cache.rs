@@ -0,0 +1,77 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/**
+ * A script file and plan cache of its own, removed even if an assert fails
+ */
+struct Project {
+    dir: PathBuf,
+}
+
+impl Project {
+    fn new(name: &str, contents: &str) -> Project {
+        let dir = std::env::temp_dir().join(format!("scriptplan-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("default.scripts.yaml"), contents).unwrap();
+        Project { dir }
+    }
+
+    fn run(&self, args: &[&str]) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_scriptplan"))
+            .arg("-s")
+            .arg(self.dir.join("default.scripts.yaml"))
+            .args(args)
+            .env("XDG_CACHE_HOME", self.dir.join("cache"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn cache_entries(&self) -> usize {
+        fs::read_dir(self.dir.join("cache").join("scriptplan")).map_or(0, |entries| entries.count())
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn plans_for_the_requested_platform_whether_cached_or_not() {
+    let project = Project::new(
+        "platform-cache",
+        "move:\n  script:\n    windows: move a b\n    default: mv a b\n",
+    );
+    let windows = ["--dry-run", "--platform", "windows", "move"];
+    let linux = ["--dry-run", "--platform", "linux", "move"];
+
+    assert_eq!(project.cache_entries(), 0);
+    assert_eq!(project.run(&windows), "$ move a b\n");
+    assert_eq!(project.cache_entries(), 1);
+    assert_eq!(project.run(&windows), "$ move a b\n");
+
+    // The entry was written for windows so this is a miss that replaces it
+    assert_eq!(project.run(&linux), "$ mv a b\n");
+    assert_eq!(project.run(&linux), "$ mv a b\n");
+    assert_eq!(project.run(&windows), "$ move a b\n");
+}
+
+#[test]
+fn shows_what_the_requested_platform_skips() {
+    let project = Project::new(
+        "platform-conditions",
+        "move:\n  series:\n    - script: move a b\n      if:\n        os: windows\n    - script: mv a b\n      if:\n        os: [linux, macos]\n",
+    );
+    assert_eq!(
+        project.run(&["--platform", "windows", "move"]),
+        "series\n  if os is windows\n    $ move a b\n  if os is linux or macos (skipped on windows)\n    $ mv a b\n"
+    );
+    assert_eq!(
+        project.run(&["--dry-run", "--platform", "linux", "move"]),
+        "series\n  if os is windows (skipped on linux)\n    $ move a b\n  if os is linux or macos\n    $ mv a b\n"
+    );
+}
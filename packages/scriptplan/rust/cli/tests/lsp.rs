@@ -0,0 +1,234 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use serde_json::{json, Value};
+
+const URI: &str = "file:///project/default.scripts.yaml";
+
+const SCRIPT_FILE: &str = "\
+build:
+  description: Builds everything
+  series:
+    - cargo build
+    - task: fmt --check
+fmt: cargo fmt
+ci:
+  series:
+    - task: build
+    - task: tset
+  unknown: true
+";
+
+/**
+ * Talks to `scriptplan lsp` the way an editor would
+ */
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+impl Client {
+    fn start() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_scriptplan"))
+            .arg("lsp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut client = Client {
+            child,
+            stdin,
+            stdout,
+            next_id: 0,
+        };
+        client.request("initialize", json!({ "capabilities": {} }));
+        client.notify("initialized", json!({}));
+        client
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            self.stdout.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                length = value.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /**
+     * Skips over any notifications sent in the meantime
+     */
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        loop {
+            let message = self.receive();
+            if message["id"] == id {
+                return message["result"].clone();
+            }
+        }
+    }
+
+    fn diagnostics(&mut self) -> Vec<(u64, String)> {
+        loop {
+            let message = self.receive();
+            if message["method"] == "textDocument/publishDiagnostics" {
+                return message["params"]["diagnostics"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|diagnostic| {
+                        (
+                            diagnostic["range"]["start"]["line"].as_u64().unwrap(),
+                            diagnostic["message"].as_str().unwrap().to_string(),
+                        )
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    fn open(&mut self, text: &str) -> Vec<(u64, String)> {
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": URI, "languageId": "yaml", "version": 1, "text": text }
+            }),
+        );
+        self.diagnostics()
+    }
+
+    fn at(&mut self, method: &str, line: u64, character: u64) -> Value {
+        self.request(method, position(line, character))
+    }
+
+    fn shutdown(mut self) {
+        self.request("shutdown", Value::Null);
+        self.notify("exit", Value::Null);
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn position(line: u64, character: u64) -> Value {
+    json!({
+        "textDocument": { "uri": URI },
+        "position": { "line": line, "character": character }
+    })
+}
+
+fn lines(locations: &Value) -> Vec<u64> {
+    locations
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+        .collect()
+}
+
+#[test]
+fn reports_problems_as_the_file_changes() {
+    let mut client = Client::start();
+    let diagnostics = client.open(SCRIPT_FILE);
+    assert!(diagnostics.iter().any(
+        |(line, message)| *line == 9 && message.starts_with("The task \"tset\" does not exist")
+    ));
+    assert!(diagnostics.iter().any(|(line, _)| *line == 10));
+
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "build: cargo build\nci:\n  series: []\n" }]
+        }),
+    );
+    assert_eq!(
+        client.diagnostics(),
+        vec![(2, "A group must contain at least one script".to_string())]
+    );
+
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 3 },
+            "contentChanges": [{ "text": "build: cargo build\n" }]
+        }),
+    );
+    assert_eq!(client.diagnostics(), vec![]);
+
+    client.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 4 },
+            "contentChanges": [{ "text": "build: cargo build\ncheck: cargo check\n" }]
+        }),
+    );
+    assert_eq!(
+        client.diagnostics(),
+        vec![(1, "\"check\" is a builtin command, so this task can only be run with `scriptplan run check` or through other tasks".to_string())]
+    );
+    client.shutdown();
+}
+
+#[test]
+fn answers_questions_about_tasks() {
+    let mut client = Client::start();
+    client.open(SCRIPT_FILE);
+
+    // Partway through `- task: build`
+    let completions = client.at("textDocument/completion", 8, 13);
+    let labels: Vec<&str> = completions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect();
+    assert_eq!(labels, vec!["build", "ci", "fmt"]);
+    assert_eq!(completions[0]["detail"], "Builds everything");
+    assert_eq!(client.at("textDocument/completion", 3, 8), json!([]));
+
+    let definition = client.at("textDocument/definition", 4, 13);
+    assert_eq!(
+        definition["range"]["start"],
+        json!({ "line": 5, "character": 0 })
+    );
+    assert_eq!(
+        definition["range"]["end"],
+        json!({ "line": 5, "character": 3 })
+    );
+
+    let mut params = position(0, 2);
+    params["context"] = json!({ "includeDeclaration": true });
+    let references = client.request("textDocument/references", params);
+    assert_eq!(lines(&references), vec![0, 8]);
+
+    let hover = client.at("textDocument/hover", 8, 13);
+    let hover = hover["contents"]["value"].as_str().unwrap();
+    assert!(hover.starts_with("**build**\n\nBuilds everything\n\n```sh\n"));
+    assert!(hover.contains("cargo build"));
+    assert!(hover.contains("cargo fmt"));
+    client.shutdown();
+}
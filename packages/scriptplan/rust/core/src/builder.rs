@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use crate::{
+    format_plan, Alias, Command, CommandGroup, Condition, Conditional, Diagnostic, NodePath,
+    Register, Reporter, RunContext, RunEvent, Script, ScriptGroup, ScriptParser, TerminalReporter,
+    VarArgs,
+};
+
+/**
+ * A set of tasks defined in code rather than loaded from a script file.
+ * It's its own ScriptParser so it runs on the same executor that the CLI uses.
+ * E.g. Plan::new().task("build", series![alias("lint"), BashCommand::from("cargo build")])
+ */
+#[derive(Debug)]
+pub struct Plan<CommandGeneric: Command> {
+    tasks: HashMap<String, Arc<Script<CommandGeneric>>>,
+}
+
+impl<CommandGeneric: Command> Default for Plan<CommandGeneric> {
+    fn default() -> Self {
+        Plan {
+            tasks: HashMap::new(),
+        }
+    }
+}
+
+impl<CommandGeneric: Command + 'static> Plan<CommandGeneric> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Adds a task, replacing any existing task with the same name
+     */
+    pub fn task(
+        mut self,
+        name: impl Into<String>,
+        script: impl Into<Script<CommandGeneric>>,
+    ) -> Self {
+        self.tasks.insert(name.into(), Arc::new(script.into()));
+        self
+    }
+
+    pub async fn run(self, task: &str, args: VarArgs) -> Result<ExitStatus, ()> {
+        self.run_with_reporter(task, args, Arc::new(TerminalReporter))
+            .await
+    }
+
+    pub async fn run_with_reporter(
+        self,
+        task: &str,
+        args: VarArgs,
+        reporter: Arc<dyn Reporter>,
+    ) -> Result<ExitStatus, ()> {
+        let context = RunContext::new(task).with_reporter(reporter);
+        self.run_in(&context, task, args).await
+    }
+
+    /**
+     * Runs a task in a context that's already been set up, like one whose stdout is being captured
+     */
+    pub async fn run_in(
+        self,
+        context: &RunContext,
+        task: &str,
+        args: VarArgs,
+    ) -> Result<ExitStatus, ()> {
+        let plan = Arc::new(self);
+        let script = plan.parse(task).map_err(|diagnostic| {
+            eprintln!("{}", diagnostic);
+        })?;
+        context.report(RunEvent::PlanResolved {
+            task: task.to_string(),
+            plan: format_plan(&script, plan.as_ref(), context),
+        });
+        script.run_as_task(&plan, context, args).await
+    }
+}
+
+impl<CommandGeneric: Command> ScriptParser<CommandGeneric> for Plan<CommandGeneric> {
+    fn parse(&self, task: &str) -> Result<Arc<Script<CommandGeneric>>, Diagnostic> {
+        self.tasks
+            .get(task)
+            .cloned()
+            .ok_or_else(|| Diagnostic::missing_task(NodePath::task(task), task, &self.task_names()))
+    }
+
+    fn task_names(&self) -> Vec<String> {
+        self.tasks.keys().cloned().collect()
+    }
+}
+
+impl<CommandGeneric: Command> From<CommandGeneric> for Script<CommandGeneric> {
+    fn from(command: CommandGeneric) -> Self {
+        Script::Command(command)
+    }
+}
+
+impl<CommandGeneric: Command> From<Alias> for Script<CommandGeneric> {
+    fn from(alias: Alias) -> Self {
+        Script::Alias(alias)
+    }
+}
+
+impl<CommandGeneric: Command> From<CommandGroup<CommandGeneric>> for Script<CommandGeneric> {
+    fn from(group: CommandGroup<CommandGeneric>) -> Self {
+        Script::Group(Box::new(group))
+    }
+}
+
+impl<CommandGeneric: Command> ScriptGroup<CommandGeneric> {
+    pub fn new(first: Script<CommandGeneric>, rest: Vec<Script<CommandGeneric>>) -> Self {
+        ScriptGroup {
+            bail: false,
+            first: Arc::new(first),
+            rest: rest.into_iter().map(Arc::new).collect(),
+        }
+    }
+}
+
+impl<CommandGeneric: Command> Script<CommandGeneric> {
+    /**
+     * Only runs this script if the condition holds
+     */
+    pub fn only_if(self, condition: Condition<CommandGeneric>) -> Self {
+        Script::Conditional(Box::new(Conditional {
+            condition,
+            script: self,
+        }))
+    }
+
+    /**
+     * Saves what this script prints to stdout as a variable for the steps after it
+     */
+    pub fn register(self, name: impl Into<String>) -> Self {
+        Script::Register(Box::new(Register {
+            stdout: Some(name.into()),
+            status: None,
+            script: self,
+        }))
+    }
+}
+
+impl Alias {
+    pub fn new(task: impl Into<String>, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Alias {
+            task: task.into(),
+            args: args
+                .into_iter()
+                .map(|arg| Arc::new(arg.into()))
+                .collect::<VecDeque<_>>(),
+            path: NodePath::default(),
+        }
+    }
+}
+
+/**
+ * Shorthand for an alias to another task without any arguments of its own
+ */
+pub fn alias(task: impl Into<String>) -> Alias {
+    Alias::new(task, Vec::<String>::new())
+}
+
+/**
+ * Runs each script one after the other. Anything that converts into a Script can be used.
+ */
+#[macro_export]
+macro_rules! series {
+    ($first:expr $(, $rest:expr)* $(,)?) => {
+        $crate::Script::from($crate::CommandGroup::Series($crate::ScriptGroup::new(
+            $first.into(),
+            vec![$($rest.into()),*],
+        )))
+    };
+}
+
+/**
+ * Runs each script at the same time. Anything that converts into a Script can be used.
+ */
+#[macro_export]
+macro_rules! parallel {
+    ($first:expr $(, $rest:expr)* $(,)?) => {
+        $crate::Script::from($crate::CommandGroup::Parallel($crate::ScriptGroup::new(
+            $first.into(),
+            vec![$($rest.into()),*],
+        )))
+    };
+}
+
+/**
+ * Runs each script at the same time with each one's stdout going into the next one's stdin.
+ * Anything that converts into a Script can be used.
+ */
+#[macro_export]
+macro_rules! pipe {
+    ($first:expr $(, $rest:expr)* $(,)?) => {
+        $crate::Script::from($crate::CommandGroup::Pipe($crate::ScriptGroup::new(
+            $first.into(),
+            vec![$($rest.into()),*],
+        )))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    #[derive(Debug)]
+    struct RecordCommand {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl fmt::Display for RecordCommand {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "record {}", self.name)
+        }
+    }
+
+    #[async_trait]
+    impl Command for RecordCommand {
+        async fn run(&self, _context: &RunContext, args: VarArgs) -> Result<ExitStatus, ()> {
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            self.log.lock().unwrap().push(
+                format!("{} {}", self.name, args.join(" "))
+                    .trim()
+                    .to_string(),
+            );
+            Ok(ExitStatus::default())
+        }
+    }
+
+    #[test]
+    fn runs_tasks_defined_in_code() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name| RecordCommand {
+            name,
+            log: log.clone(),
+        };
+
+        let plan = Plan::new()
+            .task("lint", record("lint"))
+            .task("test", record("test"))
+            .task(
+                "build",
+                series![
+                    parallel![alias("lint"), Alias::new("test", ["--all"])],
+                    record("build"),
+                    Script::from(record("skipped"))
+                        .only_if(Condition::Os(vec!["not-an-os".to_string()])),
+                ],
+            );
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let status = runtime
+            .block_on(plan.run("build", VecDeque::new()))
+            .unwrap();
+
+        assert!(status.success());
+        let mut log = log.lock().unwrap().clone();
+        // Parallel tasks can finish in any order
+        log[0..2].sort();
+        assert_eq!(log, vec!["lint", "test --all", "build"]);
+    }
+
+    #[test]
+    fn stops_alias_cycles() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name| RecordCommand {
+            name,
+            log: log.clone(),
+        };
+
+        let plan = Plan::new()
+            .task("lint", record("lint"))
+            .task("test", series![alias("lint"), alias("build")])
+            .task("build", series![alias("lint"), alias("test")]);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        assert!(runtime
+            .block_on(plan.run("build", VecDeque::new()))
+            .is_err());
+        // Running lint twice from different branches is fine
+        assert_eq!(*log.lock().unwrap(), vec!["lint", "lint"]);
+    }
+}
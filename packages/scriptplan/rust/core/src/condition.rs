@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+use async_recursion::async_recursion;
+
+use crate::{Command, RunContext};
+
+/**
+ * A predicate that decides whether a script should run at all.
+ * Evaluated right before the script it guards, not when the plan is parsed.
+ */
+#[derive(Debug)]
+pub enum Condition<CommandGeneric: Command> {
+    /// Matches if the OS being planned for (as in std::env::consts::OS) is any of the listed values
+    Os(Vec<String>),
+    /// Matches if the current CPU architecture (as in std::env::consts::ARCH) is any of the listed values
+    Arch(Vec<String>),
+    EnvSet(String),
+    EnvEquals(String, String),
+    FileExists(PathBuf),
+    /// Matches if the command exits successfully
+    Probe(CommandGeneric),
+    All(Vec<Condition<CommandGeneric>>),
+    Any(Vec<Condition<CommandGeneric>>),
+    Not(Box<Condition<CommandGeneric>>),
+}
+
+impl<CommandGeneric: Command> Condition<CommandGeneric> {
+    #[async_recursion]
+    pub async fn evaluate(&self, context: &RunContext) -> bool {
+        match self {
+            Self::Os(names) => names.iter().any(|name| name == context.os()),
+            Self::Arch(names) => names.iter().any(|name| name == env::consts::ARCH),
+            Self::EnvSet(name) => env::var_os(name).is_some(),
+            Self::EnvEquals(name, value) => match context.substitute_vars(value).await {
+                Ok(value) => env::var(name).is_ok_and(|actual| actual == value),
+                Err(()) => false,
+            },
+            Self::FileExists(path) => path.exists(),
+            Self::Probe(command) => command
+                .run(&context.discarding_stdout(), VecDeque::new())
+                .await
+                .is_ok_and(|status| status.success()),
+            Self::All(conditions) => {
+                for condition in conditions {
+                    if !condition.evaluate(context).await {
+                        return false;
+                    }
+                }
+                true
+            }
+            Self::Any(conditions) => {
+                for condition in conditions {
+                    if condition.evaluate(context).await {
+                        return true;
+                    }
+                }
+                false
+            }
+            Self::Not(condition) => !condition.evaluate(context).await,
+        }
+    }
+
+    /**
+     * Whether the condition holds for the platform being planned for, if that's all it depends on.
+     * Lets a dry run show what would be skipped without probing anything.
+     */
+    pub fn planned(&self, context: &RunContext) -> Option<bool> {
+        match self {
+            Self::Os(names) => Some(names.iter().any(|name| name == context.os())),
+            Self::All(conditions) => {
+                let planned: Vec<_> = conditions.iter().map(|c| c.planned(context)).collect();
+                if planned.contains(&Some(false)) {
+                    Some(false)
+                } else {
+                    planned
+                        .into_iter()
+                        .collect::<Option<Vec<_>>>()
+                        .map(|_| true)
+                }
+            }
+            Self::Any(conditions) => {
+                let planned: Vec<_> = conditions.iter().map(|c| c.planned(context)).collect();
+                if planned.contains(&Some(true)) {
+                    Some(true)
+                } else {
+                    planned
+                        .into_iter()
+                        .collect::<Option<Vec<_>>>()
+                        .map(|_| false)
+                }
+            }
+            Self::Not(condition) => condition.planned(context).map(|planned| !planned),
+            _ => None,
+        }
+    }
+}
+
+fn write_joined(
+    f: &mut fmt::Formatter<'_>,
+    items: &[impl fmt::Display],
+    separator: &str,
+) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}", separator)?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+impl<CommandGeneric: Command> fmt::Display for Condition<CommandGeneric> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Os(names) => {
+                write!(f, "os is ")?;
+                write_joined(f, names, " or ")
+            }
+            Self::Arch(names) => {
+                write!(f, "arch is ")?;
+                write_joined(f, names, " or ")
+            }
+            Self::EnvSet(name) => write!(f, "${} is set", name),
+            Self::EnvEquals(name, value) => write!(f, "${} is \"{}\"", name, value),
+            Self::FileExists(path) => write!(f, "{} exists", path.display()),
+            Self::Probe(_) => write!(f, "probe command succeeds"),
+            Self::All(conditions) => {
+                write!(f, "(")?;
+                write_joined(f, conditions, " and ")?;
+                write!(f, ")")
+            }
+            Self::Any(conditions) => {
+                write!(f, "(")?;
+                write_joined(f, conditions, " or ")?;
+                write!(f, ")")
+            }
+            Self::Not(condition) => write!(f, "not {}", condition),
+        }
+    }
+}
+
+/**
+ * A script that only runs if its condition holds. Otherwise it's skipped and counts as a success.
+ */
+#[derive(Debug)]
+pub struct Conditional<CommandGeneric: Command> {
+    pub condition: Condition<CommandGeneric>,
+    pub script: crate::Script<CommandGeneric>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use std::process::ExitStatus;
+
+    #[derive(Debug)]
+    struct SucceedingCommand;
+
+    impl fmt::Display for SucceedingCommand {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "true")
+        }
+    }
+
+    #[async_trait]
+    impl Command for SucceedingCommand {
+        async fn run(
+            &self,
+            _context: &RunContext,
+            _args: crate::VarArgs,
+        ) -> Result<ExitStatus, ()> {
+            Ok(ExitStatus::default())
+        }
+    }
+
+    #[derive(Debug)]
+    struct EchoCommand;
+
+    impl fmt::Display for EchoCommand {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "echo probed")
+        }
+    }
+
+    #[async_trait]
+    impl Command for EchoCommand {
+        async fn run(&self, context: &RunContext, _args: crate::VarArgs) -> Result<ExitStatus, ()> {
+            let process = tokio::process::Command::new("echo")
+                .arg("probed")
+                .stdout(context.stdout_stdio().map_err(|_| ())?)
+                .spawn()
+                .map_err(|_| ())?;
+            context.wait_for(process).await.map_err(|_| ())
+        }
+    }
+
+    #[test]
+    fn matches_current_platform() {
+        let condition: Condition<SucceedingCommand> = Condition::All(vec![
+            Condition::Os(vec!["not-an-os".to_string(), env::consts::OS.to_string()]),
+            Condition::Arch(vec![env::consts::ARCH.to_string()]),
+        ]);
+        assert!(block_on(condition.evaluate(&RunContext::default())));
+    }
+
+    #[test]
+    fn checks_the_planned_platform() {
+        let windows = RunContext::default().with_platform(Some(crate::Platform::Windows));
+        let os = |name: &str| Condition::<SucceedingCommand>::Os(vec![name.to_string()]);
+        let probe = || Condition::Probe(SucceedingCommand);
+
+        assert!(block_on(os("windows").evaluate(&windows)));
+        assert!(!block_on(os("linux").evaluate(&windows)));
+        assert_eq!(os("linux").planned(&windows), Some(false));
+        assert_eq!(
+            Condition::Not(Box::new(os("linux"))).planned(&windows),
+            Some(true)
+        );
+        // Anything else only gets decided when it's run
+        assert_eq!(probe().planned(&windows), None);
+        assert_eq!(
+            Condition::All(vec![os("linux"), probe()]).planned(&windows),
+            Some(false)
+        );
+        assert_eq!(
+            Condition::All(vec![os("windows"), probe()]).planned(&windows),
+            None
+        );
+        assert_eq!(
+            Condition::Any(vec![probe(), os("windows")]).planned(&windows),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn combinators() {
+        let condition: Condition<SucceedingCommand> = Condition::Any(vec![
+            Condition::Not(Box::new(Condition::Probe(SucceedingCommand))),
+            Condition::FileExists("./definitely/does/not/exist".into()),
+        ]);
+        assert!(!block_on(condition.evaluate(&RunContext::default())));
+        assert_eq!(
+            condition.to_string(),
+            "(not probe command succeeds or ./definitely/does/not/exist exists)"
+        );
+    }
+
+    #[test]
+    fn probes_dont_print() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let condition = Condition::Probe(EchoCommand);
+        let (stdout, matched) = runtime
+            .block_on(
+                RunContext::default()
+                    .capture_stdout(|context| async move { condition.evaluate(&context).await }),
+            )
+            .unwrap();
+        assert!(matched);
+        assert_eq!(stdout, "");
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{PipeReader, PipeWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::RwLock;
+
+use crate::{Diagnostic, LazyVar, NodePath, Platform, Reporter, RunEvent, TerminalReporter};
+
+/**
+ * State that gets passed down through a run. Each branch gets its own copy so siblings don't affect each other.
+ */
+#[derive(Clone)]
+pub struct RunContext {
+    /// The tasks currently being run, outermost first
+    pub alias_chain: Vec<String>,
+    pub reporter: Arc<dyn Reporter>,
+    /// The command currently being run, if any
+    pub command_id: Option<usize>,
+    /// Scripts in the same lane run one after the other. Each extra branch of a parallel group gets a new one.
+    pub lane: usize,
+    /// Whether this is somewhere inside a branch of a parallel group
+    pub in_parallel: bool,
+    /// Set when this is a member of a pipe group that isn't the first one
+    pub stdin_pipe: Option<Arc<PipeReader>>,
+    /// Set when this is a member of a pipe group that isn't the last one
+    pub stdout_pipe: Option<Arc<PipeWriter>>,
+    /// Set while probing a condition, whose output isn't part of the task's
+    pub discard_stdout: bool,
+    /// Set inside a group that already holds the terminal lock for its members
+    pub holds_terminal: bool,
+    /// The platform being planned for, which `os` conditions check. None means whatever this is running on.
+    pub platform: Option<Platform>,
+    /// Values registered by earlier steps. Shared by everything in the same scope.
+    pub(crate) registered: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Defined up front and shared by the whole run
+    pub(crate) defined: Arc<BTreeMap<String, LazyVar>>,
+    /// Shared by every branch of a run so that ids are unique
+    next_id: Arc<AtomicUsize>,
+    next_lane: Arc<AtomicUsize>,
+    pub(crate) terminal: Arc<RwLock<()>>,
+}
+
+impl Default for RunContext {
+    fn default() -> Self {
+        RunContext {
+            alias_chain: Vec::new(),
+            reporter: Arc::new(TerminalReporter),
+            command_id: None,
+            lane: 0,
+            in_parallel: false,
+            stdin_pipe: None,
+            stdout_pipe: None,
+            discard_stdout: false,
+            holds_terminal: false,
+            platform: None,
+            registered: Arc::default(),
+            defined: Arc::default(),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            next_lane: Arc::new(AtomicUsize::new(1)),
+            terminal: Arc::new(RwLock::new(())),
+        }
+    }
+}
+
+impl fmt::Debug for RunContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunContext")
+            .field("alias_chain", &self.alias_chain)
+            .field("command_id", &self.command_id)
+            .field("lane", &self.lane)
+            .field("in_parallel", &self.in_parallel)
+            .field("registered", &self.registered)
+            .finish()
+    }
+}
+
+impl RunContext {
+    /**
+     * A context for running the given task at the top level
+     */
+    pub fn new(task: &str) -> Self {
+        RunContext {
+            alias_chain: vec![task.to_string()],
+            ..RunContext::default()
+        }
+    }
+
+    pub fn with_reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    pub fn with_platform(mut self, platform: Option<Platform>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /**
+     * The OS name (as in std::env::consts::OS) of the platform being planned for
+     */
+    pub fn os(&self) -> &'static str {
+        self.platform
+            .map_or(std::env::consts::OS, |platform| platform.as_str())
+    }
+
+    pub fn report(&self, event: RunEvent) {
+        self.reporter.report(self, event);
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn in_new_lane(&self) -> RunContext {
+        let mut context = self.clone();
+        context.lane = self.next_lane.fetch_add(1, Ordering::Relaxed);
+        context
+    }
+
+    pub fn in_parallel(&self) -> RunContext {
+        let mut context = self.clone();
+        context.in_parallel = true;
+        context
+    }
+
+    pub fn discarding_stdout(&self) -> RunContext {
+        let mut context = self.clone();
+        context.discard_stdout = true;
+        context
+    }
+
+    /**
+     * The task that whatever's currently running belongs to
+     */
+    pub fn task(&self) -> String {
+        self.alias_chain.last().cloned().unwrap_or_default()
+    }
+
+    /**
+     * Gives the command a fresh id and reports that it's started
+     */
+    pub fn start_command(&self, command: &impl fmt::Display) -> RunContext {
+        let mut context = self.clone();
+        let id = self.next_id();
+        context.command_id = Some(id);
+        context.report(RunEvent::CommandStarted {
+            id,
+            task: self.task(),
+            command: command.to_string(),
+        });
+        context
+    }
+
+    /**
+     * Fails if the task is already being run further up, since running it again would never end
+     */
+    pub fn enter_alias(&self, task: &str) -> Result<RunContext, Diagnostic> {
+        if let Some(position) = self.alias_chain.iter().position(|active| active == task) {
+            let path = match self.alias_chain.last() {
+                Some(aliasing_task) => NodePath::task(aliasing_task),
+                None => NodePath::task(task),
+            };
+            return Err(Diagnostic::alias_cycle(
+                path,
+                &self.alias_chain[position..],
+                task,
+            ));
+        }
+
+        let mut context = self.clone();
+        context.alias_chain.push(task.to_string());
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_rejects_tasks_that_are_still_running() {
+        let build = RunContext::new("build");
+        let lint = build.enter_alias("lint").unwrap();
+        // Siblings can alias the same task
+        assert!(build.enter_alias("lint").is_ok());
+        assert!(lint.enter_alias("test").is_ok());
+
+        assert_eq!(
+            lint.enter_alias("build").unwrap_err().to_string(),
+            "lint: Alias cycle: build -> lint -> build"
+        );
+    }
+
+    #[test]
+    fn gives_each_branch_its_own_lane() {
+        let build = RunContext::new("build");
+        let branch = build.in_new_lane();
+        let nested = branch.in_new_lane();
+        assert_eq!(build.lane, 0);
+        assert_eq!(branch.lane, 1);
+        assert_eq!(nested.lane, 2);
+        assert_eq!(branch.clone().lane, 1);
+    }
+}
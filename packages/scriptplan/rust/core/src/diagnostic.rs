@@ -0,0 +1,184 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/**
+ * Where a node is within a script file. The first segment is always the task's name.
+ * The segments mirror the keys in the file so that loaders can map them back to source locations.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NodePath(pub Vec<PathSegment>);
+
+impl NodePath {
+    pub fn task(name: &str) -> Self {
+        NodePath(vec![PathSegment::Key(name.to_string())])
+    }
+
+    pub fn key(&self, key: &str) -> Self {
+        let mut segments = self.0.clone();
+        segments.push(PathSegment::Key(key.to_string()));
+        NodePath(segments)
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        let mut segments = self.0.clone();
+        segments.push(PathSegment::Index(index));
+        NodePath(segments)
+    }
+
+    pub fn task_name(&self) -> Option<&str> {
+        match self.0.first() {
+            Some(PathSegment::Key(name)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{}", key)?,
+                PathSegment::Key(key) if i == 1 => write!(f, ": {}", key)?,
+                PathSegment::Key(key) => write!(f, ".{}", key)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Where a node starts in its source file. Lines and columns start at 1.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    /// In characters. Spans never cross lines.
+    pub length: usize,
+}
+
+/**
+ * A problem with a script file that's specific enough to point the user at
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub path: NodePath,
+    pub message: String,
+    /// Filled in by whatever loaded the file, since only it knows where the path ended up
+    pub span: Option<Span>,
+    /// Whether the problem is with the last key in the path rather than its value
+    pub on_key: bool,
+}
+
+impl Diagnostic {
+    pub fn new(path: NodePath, message: impl Into<String>) -> Self {
+        Diagnostic {
+            path,
+            message: message.into(),
+            span: None,
+            on_key: false,
+        }
+    }
+
+    pub fn about_key(mut self) -> Self {
+        self.on_key = true;
+        self
+    }
+
+    /**
+     * Suggests the closest task name in case it was a typo
+     */
+    pub fn missing_task(path: NodePath, task: &str, task_names: &[String]) -> Self {
+        let mut message = format!("The task \"{}\" does not exist", task);
+        if let Some(suggestion) = did_you_mean(task, task_names.iter().map(String::as_str)) {
+            message += &format!(". Did you mean \"{}\"?", suggestion);
+        }
+        Diagnostic::new(path, message)
+    }
+
+    /**
+     * The cycle is the tasks that alias each other in order, which ends up back at the first of them
+     */
+    pub fn alias_cycle(path: NodePath, cycle: &[String], repeated: &str) -> Self {
+        let mut tasks: Vec<&str> = cycle.iter().map(String::as_str).collect();
+        tasks.push(repeated);
+        Diagnostic::new(path, format!("Alias cycle: {}", tasks.join(" -> ")))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.0.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/**
+ * Levenshtein distance where swapping two adjacent characters only counts as one edit
+ */
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/**
+ * The closest candidate, as long as it's close enough that the name was likely a typo of it
+ */
+pub fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_close_names() {
+        let tasks = ["build", "test", "deploy"];
+        assert_eq!(did_you_mean("biuld", tasks), Some("build"));
+        assert_eq!(did_you_mean("tset", tasks), Some("test"));
+        assert_eq!(did_you_mean("lint", tasks), None);
+    }
+
+    #[test]
+    fn formats_paths_like_the_file() {
+        let path = NodePath::task("build").key("series").index(1).key("task");
+        assert_eq!(path.to_string(), "build: series[1].task");
+    }
+}
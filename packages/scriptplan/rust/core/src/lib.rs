@@ -1,34 +1,93 @@
 use std::collections::vec_deque::Iter;
 use std::collections::VecDeque;
+use std::fmt;
 use std::iter::{Chain, Iterator, Once};
 use std::process::ExitStatus;
-use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
 
 use futures::future::join_all;
-use futures::join;
 
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 
 use scriptplan_lang_utils::{apply_args, has_parameters};
 
+mod builder;
+mod condition;
+mod context;
+mod diagnostic;
+mod pattern;
+mod plan;
+mod platform;
+mod reporter;
+mod terminal;
+mod validate;
+mod vars;
+pub use builder::*;
+pub use condition::*;
+pub use context::*;
+pub use diagnostic::*;
+pub use pattern::*;
+pub use plan::*;
+pub use platform::*;
+pub use reporter::*;
+pub use terminal::*;
+pub use validate::*;
+pub use vars::*;
+
+/**
+ * Display is used when a plan gets printed instead of run.
+ * Commands have to be thread safe so that parallel groups can be spawned onto separate threads.
+ * Processes should be spawned with the context's stdin_stdio, stdout_stdio and output_stdio (for stderr)
+ * and waited on with its wait_for so that pipes get connected up and reporters see their output.
+ * The variables the command uses (see RunContext::vars_in) should be passed along as environment variables.
+ */
+#[async_trait]
+pub trait Command: fmt::Debug + fmt::Display + Send + Sync {
+    async fn run(&self, context: &RunContext, args: VarArgs) -> Result<ExitStatus, ()>;
+
+    /**
+     * How many positional arguments the command references, if the backend is able to tell.
+     * Used to spot arguments that can never be provided.
+     */
+    fn required_args(&self) -> Option<usize> {
+        None
+    }
+}
+
+/**
+ * Lets commands from different backends (E.g. bash and nu) be mixed within the same plan
+ */
+pub type DynCommand = Box<dyn Command>;
+
 #[async_trait]
-pub trait Command {
-    async fn run(&self, args: VarArgs) -> Result<ExitStatus, ()>;
+impl<CommandGeneric: Command + ?Sized> Command for Box<CommandGeneric> {
+    async fn run(&self, context: &RunContext, args: VarArgs) -> Result<ExitStatus, ()> {
+        self.as_ref().run(context, args).await
+    }
+
+    fn required_args(&self) -> Option<usize> {
+        self.as_ref().required_args()
+    }
 }
 
 pub type VarArgs = VecDeque<Arc<String>>;
 #[derive(Debug)]
 pub struct ScriptGroup<CommandGeneric: Command> {
+    /// Series stop at the first member that fails instead of carrying on
     pub bail: bool,
     // Enforces that there's always at least 1 script
-    pub first: Script<CommandGeneric>,
-    pub rest: VecDeque<Script<CommandGeneric>>,
+    // Scripts are reference counted so that they can be moved into spawned tasks
+    pub first: Arc<Script<CommandGeneric>>,
+    pub rest: VecDeque<Arc<Script<CommandGeneric>>>,
 }
 
+type ScriptGroupIter<'a, CommandGeneric> =
+    Chain<Once<&'a Arc<Script<CommandGeneric>>>, Iter<'a, Arc<Script<CommandGeneric>>>>;
+
 impl<CommandGeneric: Command> ScriptGroup<CommandGeneric> {
-    fn iter(&self) -> Chain<Once<&'_ Script<CommandGeneric>>, Iter<'_, Script<CommandGeneric>>> {
+    fn iter(&self) -> ScriptGroupIter<'_, CommandGeneric> {
         std::iter::once(&self.first).chain(self.rest.iter())
     }
 }
@@ -38,6 +97,27 @@ fn clone_args(args: &VarArgs) -> VarArgs {
     return args.iter().map(|x| x.clone()).collect();
 }
 
+/**
+ * Aliases inside a group only receive the group's arguments if they explicitly reference them
+ */
+fn member_args<CommandGeneric: Command>(
+    script: &Script<CommandGeneric>,
+    args: &VarArgs,
+) -> VarArgs {
+    match script {
+        Script::Alias(alias) => {
+            if has_parameters(&alias.args) {
+                clone_args(args)
+            } else {
+                VecDeque::new()
+            }
+        }
+        Script::Conditional(conditional) => member_args(&conditional.script, args),
+        Script::Register(register) => member_args(&register.script, args),
+        _ => clone_args(args),
+    }
+}
+
 /**
  * TODO: Choose a better name
  */
@@ -45,41 +125,83 @@ fn clone_args(args: &VarArgs) -> VarArgs {
 pub enum CommandGroup<CommandGeneric: Command> {
     Parallel(ScriptGroup<CommandGeneric>),
     Series(ScriptGroup<CommandGeneric>),
+    /// Runs every member at once with each one's stdout connected to the next one's stdin
+    Pipe(ScriptGroup<CommandGeneric>),
 }
 
+/**
+ * The first failure decides the status of a group
+ */
 fn merge_status(status1: ExitStatus, status2: ExitStatus) -> ExitStatus {
     if !status1.success() {
-        return status2;
+        status1
+    } else {
+        status2
+    }
+}
+
+/**
+ * Every branch of a parallel group gets to finish, so the first failing status wins. A branch that couldn't run at
+ * all (or panicked) only decides the result when no other branch failed with a status.
+ */
+fn merge_results(results: Vec<Result<ExitStatus, ()>>) -> Result<ExitStatus, ()> {
+    let mut merged: Option<ExitStatus> = None;
+    let mut errored = false;
+    for result in results {
+        match result {
+            Ok(status) => merged = Some(merged.map_or(status, |prev| merge_status(prev, status))),
+            Err(()) => errored = true,
+        }
+    }
+    match merged {
+        Some(status) if !errored || !status.success() => Ok(status),
+        _ => Err(()),
     }
-    return status1;
 }
 
 impl<CommandGeneric: Command> CommandGroup<CommandGeneric> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Parallel(_) => "parallel",
+            Self::Series(_) => "series",
+            Self::Pipe(_) => "pipe",
+        }
+    }
+
+    pub fn group(&self) -> &ScriptGroup<CommandGeneric> {
+        match self {
+            Self::Parallel(group) | Self::Series(group) | Self::Pipe(group) => group,
+        }
+    }
+}
+
+/**
+ * Like bash's pipefail, the rightmost member that failed decides the status
+ */
+fn pipefail(results: &[Result<ExitStatus, ()>]) -> Result<ExitStatus, ()> {
+    let failed =
+        |result: &Result<ExitStatus, ()>| !matches!(result, Ok(status) if status.success());
+    let decider = results
+        .iter()
+        .rposition(failed)
+        .unwrap_or(results.len() - 1);
+    results[decider]
+}
+
+impl<CommandGeneric: Command + 'static> CommandGroup<CommandGeneric> {
     async fn run(
         &self,
-        parser: &impl ScriptParser<CommandGeneric>,
+        parser: &Arc<impl ScriptParser<CommandGeneric> + 'static>,
+        context: &RunContext,
         args: VarArgs,
     ) -> Result<ExitStatus, ()> {
-        async fn run_script<'a, CommandGeneric: Command>(
-            script: &'a Script<CommandGeneric>,
+        async fn run_script<CommandGeneric: Command + 'static>(
+            script: &Script<CommandGeneric>,
             args: &VarArgs,
-            parser: &impl ScriptParser<CommandGeneric>,
+            parser: &Arc<impl ScriptParser<CommandGeneric> + 'static>,
+            context: &RunContext,
         ) -> Result<ExitStatus, ()> {
-            match script {
-                Script::Alias(alias) => {
-                    alias
-                        .run(
-                            parser,
-                            if has_parameters(&alias.args) {
-                                clone_args(&args)
-                            } else {
-                                VecDeque::new()
-                            },
-                        )
-                        .await
-                }
-                default => default.run(parser, clone_args(&args)).await,
-            }
+            script.run(parser, context, member_args(script, args)).await
         }
         // TODO: Figure out what to do with args
         match self {
@@ -88,64 +210,126 @@ impl<CommandGeneric: Command> CommandGroup<CommandGeneric> {
                     println!("Warning: Bail in parallel groups are currently not supported");
                 }
 
-                let mut promises = Vec::<_>::new();
+                let context = context.in_parallel();
+                let handles: Vec<_> = group
+                    .iter()
+                    .enumerate()
+                    .map(|(i, script)| {
+                        let script = script.clone();
+                        let parser = parser.clone();
+                        // The first branch carries on in the group's lane
+                        // Branches get their own scope so that what they register doesn't race
+                        let context = if i == 0 {
+                            context.in_new_scope()
+                        } else {
+                            context.in_new_lane().in_new_scope()
+                        };
+                        let script_args = member_args(&script, &args);
+                        tokio::spawn(
+                            async move { script.run(&parser, &context, script_args).await },
+                        )
+                    })
+                    .collect();
 
-                let mut group_iter = group.iter();
-                let mut command = group_iter.next().unwrap();
+                let results: Vec<Result<ExitStatus, ()>> = join_all(handles)
+                    .await
+                    .into_iter()
+                    // A task that panicked is treated the same as one that errored
+                    .map(|joined| joined.unwrap_or(Err(())))
+                    .collect();
+                merge_results(results)
+            }
+            Self::Pipe(group) => {
+                let member_count = group.rest.len() + 1;
+                let mut pipes = Vec::new();
+                for _ in 1..member_count {
+                    pipes.push(std::io::pipe().map_err(|err| {
+                        eprintln!("Unable to create a pipe: {}", err);
+                    })?);
+                }
 
-                while let Some(next_command) = group_iter.next() {
-                    promises.push(run_script(command, &args, parser));
+                // Members wait on each other, so one of them waiting for its own lock behind an interactive branch
+                // that's waiting for the rest to finish would never get anywhere
+                let _terminal = context.lock_terminal(&Stdin::Null).await;
+                let context = &context.holding_terminal();
 
-                    command = next_command;
-                }
+                let mut stdin_pipe = context.stdin_pipe.clone();
+                let mut pipes = pipes.into_iter();
+                let handles: Vec<_> = group
+                    .iter()
+                    .enumerate()
+                    .map(|(i, script)| {
+                        let script = script.clone();
+                        let parser = parser.clone();
+                        let mut context = if i == 0 {
+                            context.in_new_scope()
+                        } else {
+                            context.in_new_lane().in_new_scope()
+                        };
+                        context.stdin_pipe = stdin_pipe.take();
+                        // The last member writes wherever the group does
+                        if let Some((reader, writer)) = pipes.next() {
+                            context.stdout_pipe = Some(Arc::new(writer));
+                            stdin_pipe = Some(Arc::new(reader));
+                        }
+                        let script_args = member_args(&script, &args);
+                        // The member's ends of its pipes are closed once this finishes and drops the context
+                        tokio::spawn(
+                            async move { script.run(&parser, &context, script_args).await },
+                        )
+                    })
+                    .collect();
 
-                let last_result = run_script(command, &args, parser);
-                let (results, last) = join!(join_all(promises), last_result);
-
-                let final_status =
-                    results
-                        .into_iter()
-                        .fold(last.unwrap(), |prev_exit_status, this_result| {
-                            if let Ok(current_status) = this_result {
-                                return merge_status(prev_exit_status, current_status);
-                            } else {
-                                // TODO: Do something with the error
-                                return prev_exit_status;
-                            }
-                        });
-
-                return Ok(final_status);
+                let results: Vec<Result<ExitStatus, ()>> = join_all(handles)
+                    .await
+                    .into_iter()
+                    .map(|joined| joined.unwrap_or(Err(())))
+                    .collect();
+                pipefail(&results)
             }
             Self::Series(group) => {
+                let context = &context.in_new_scope();
+                if group.bail {
+                    let mut exit_status = run_script(&group.first, &args, parser, context).await?;
+                    for command in group.rest.iter() {
+                        if !exit_status.success() {
+                            break;
+                        }
+                        exit_status = run_script(command, &args, parser, context).await?;
+                    }
+                    return Ok(exit_status);
+                }
                 let mut rest_iter = group.rest.iter();
                 if let Some(last_command) = rest_iter.next_back() {
-                    let mut exit_status = run_script(&group.first, &args, parser).await.unwrap();
+                    let mut exit_status = run_script(&group.first, &args, parser, context).await?;
 
                     for command in rest_iter {
                         exit_status = merge_status(
                             exit_status,
-                            run_script(&command, &args, parser).await.unwrap(),
+                            run_script(command, &args, parser, context).await?,
                         );
                     }
 
                     exit_status = merge_status(
                         exit_status,
-                        run_script(last_command, &args, parser).await.unwrap(),
+                        run_script(last_command, &args, parser, context).await?,
                     );
 
                     Ok(exit_status)
                 } else {
-                    Ok(run_script(&group.first, &args, parser).await.unwrap())
+                    run_script(&group.first, &args, parser, context).await
                 }
             }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Alias {
     pub task: String,
     pub args: VarArgs,
+    /// Where the alias was written, so that a missing or cyclic task gets pinned on it. Empty when built in code.
+    pub path: NodePath,
 }
 
 /**
@@ -156,56 +340,174 @@ pub enum Script<CommandGeneric: Command> {
     Command(CommandGeneric),
     Group(Box<CommandGroup<CommandGeneric>>),
     Alias(Alias),
+    Conditional(Box<Conditional<CommandGeneric>>),
+    Register(Box<Register<CommandGeneric>>),
+}
+
+/**
+ * Saves what the script printed to stdout (trimmed) and/or its exit status as variables
+ * so that later steps in the same scope can use them
+ */
+#[derive(Debug)]
+pub struct Register<CommandGeneric: Command> {
+    pub stdout: Option<String>,
+    pub status: Option<String>,
+    pub script: Script<CommandGeneric>,
 }
 
 impl Alias {
-    #[async_recursion(?Send)]
-    pub async fn run<CommandGeneric: Command>(
+    #[async_recursion]
+    pub async fn run<CommandGeneric: Command + 'static>(
         &self,
-        parser: &impl ScriptParser<CommandGeneric>,
+        parser: &Arc<impl ScriptParser<CommandGeneric> + 'static>,
+        context: &RunContext,
         args: VarArgs,
     ) -> Result<ExitStatus, ()> {
+        let report = |diagnostic: Diagnostic| {
+            eprintln!("{}", parser.render(&diagnostic));
+        };
+        let context = context
+            .enter_alias(&self.task)
+            .map_err(|diagnostic| report(self.blame(diagnostic)))?;
+
+        let mut own_args = VarArgs::new();
+        for arg in self.args.iter() {
+            own_args.push_back(Arc::new(context.substitute_vars(arg).await?));
+        }
         let final_args = (|| {
-            let has_params = has_parameters(&self.args);
+            let has_params = has_parameters(&own_args);
 
             if has_params {
-                apply_args(&self.args, &args)
+                apply_args(&own_args, &args)
             } else {
-                let joined_args: VecDeque<Arc<String>> = self
-                    .args
-                    .iter()
-                    .into_iter()
-                    .map(|arg| arg.clone())
-                    .chain(args.into_iter())
-                    .collect();
+                let joined_args: VecDeque<Arc<String>> =
+                    own_args.iter().cloned().chain(args.into_iter()).collect();
 
                 return joined_args;
             }
         })();
 
-        parser
-            .parse(self.task.as_str())
-            .unwrap()
-            .run(parser, final_args)
-            .await
+        let script = parser.parse(self.task.as_str()).map_err(|diagnostic| {
+            // Anything other than the task not existing is a problem inside the task itself
+            if parser.task_names().contains(&self.task) {
+                report(diagnostic)
+            } else {
+                report(self.blame(diagnostic))
+            }
+        })?;
+        script.run_as_task(parser, &context, final_args).await
+    }
+
+    fn blame(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if !self.path.0.is_empty() {
+            diagnostic.path = self.path.clone();
+        }
+        diagnostic
     }
 }
 
-impl<CommandGeneric: Command> Script<CommandGeneric> {
-    #[async_recursion(?Send)]
+impl<CommandGeneric: Command + 'static> Script<CommandGeneric> {
+    #[async_recursion]
     pub async fn run(
         &self,
-        parser: &impl ScriptParser<CommandGeneric>,
+        parser: &Arc<impl ScriptParser<CommandGeneric> + 'static>,
+        context: &RunContext,
         args: VarArgs,
     ) -> Result<ExitStatus, ()> {
         match self {
-            Script::Command(cmd) => cmd.run(args).await,
-            Script::Group(group) => group.run(parser, args).await,
-            Script::Alias(alias) => alias.run(parser, args).await,
+            Script::Command(cmd) => {
+                let started = Instant::now();
+                let command_context = context.start_command(cmd);
+                let result = cmd.run(&command_context, args).await;
+                context.report(RunEvent::CommandFinished {
+                    id: command_context.command_id.unwrap(),
+                    status: status_code(&result),
+                    duration: started.elapsed(),
+                });
+                result
+            }
+            Script::Group(group) => {
+                let started = Instant::now();
+                let id = context.next_id();
+                context.report(RunEvent::GroupStarted {
+                    id,
+                    task: context.task(),
+                    kind: group.kind(),
+                });
+                let result = group.run(parser, context, args).await;
+                context.report(RunEvent::GroupFinished {
+                    id,
+                    status: status_code(&result),
+                    duration: started.elapsed(),
+                });
+                result
+            }
+            Script::Alias(alias) => alias.run(parser, context, args).await,
+            Script::Conditional(conditional) => {
+                if conditional.condition.evaluate(context).await {
+                    conditional.script.run(parser, context, args).await
+                } else {
+                    context.report(RunEvent::Skipped {
+                        task: context.task(),
+                        condition: conditional.condition.to_string(),
+                    });
+                    Ok(ExitStatus::default())
+                }
+            }
+            Script::Register(register) => {
+                let result = match &register.stdout {
+                    Some(name) => {
+                        let (stdout, result) = context
+                            .capture_stdout(|context| async move {
+                                register.script.run(parser, &context, args).await
+                            })
+                            .await?;
+                        context.register(name, stdout.trim());
+                        result
+                    }
+                    None => register.script.run(parser, context, args).await,
+                };
+                if let Some(name) = &register.status {
+                    let code = status_code(&result).map_or(String::new(), |code| code.to_string());
+                    context.register(name, code);
+                }
+                result
+            }
         }
     }
+
+    /**
+     * Runs the script as the task at the end of the context's alias chain, reporting when it starts and finishes
+     */
+    pub async fn run_as_task(
+        &self,
+        parser: &Arc<impl ScriptParser<CommandGeneric> + 'static>,
+        context: &RunContext,
+        args: VarArgs,
+    ) -> Result<ExitStatus, ()> {
+        let started = Instant::now();
+        context.report(RunEvent::TaskStarted {
+            task: context.task(),
+        });
+        let result = self.run(parser, context, args).await;
+        context.report(RunEvent::TaskFinished {
+            task: context.task(),
+            status: status_code(&result),
+            duration: started.elapsed(),
+        });
+        result
+    }
 }
 
-pub trait ScriptParser<CommandGeneric: Command> {
-    fn parse(&self, task: &str) -> Result<Rc<Script<CommandGeneric>>, ()>;
+pub trait ScriptParser<CommandGeneric: Command>: Send + Sync {
+    fn parse(&self, task: &str) -> Result<Arc<Script<CommandGeneric>>, Diagnostic>;
+
+    fn task_names(&self) -> Vec<String>;
+
+    /**
+     * Parsers that know where their tasks came from can show the offending source
+     */
+    fn render(&self, diagnostic: &Diagnostic) -> String {
+        diagnostic.to_string()
+    }
 }
@@ -0,0 +1,78 @@
+/**
+ * Task names are namespaced with dots (E.g. format.rust.fmt) so patterns work a segment at a time.
+ * `*` matches any part of a single segment and `**` matches one or more whole segments.
+ */
+pub fn is_task_pattern(name: &str) -> bool {
+    name.contains('*')
+}
+
+fn segment_matches(pattern: &[char], segment: &[char]) -> bool {
+    match pattern.split_first() {
+        None => segment.is_empty(),
+        Some(('*', rest)) => (0..=segment.len()).any(|i| segment_matches(rest, &segment[i..])),
+        Some((c, rest)) => segment.first() == Some(c) && segment_matches(rest, &segment[1..]),
+    }
+}
+
+fn segments_match(pattern: &[&str], name: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&"**", rest)) => (1..=name.len()).any(|i| segments_match(rest, &name[i..])),
+        Some((segment_pattern, rest)) => match name.split_first() {
+            Some((segment, name_rest)) => {
+                let segment_pattern: Vec<char> = segment_pattern.chars().collect();
+                let segment: Vec<char> = segment.chars().collect();
+                segment_matches(&segment_pattern, &segment) && segments_match(rest, name_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+pub fn matches_task_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let name: Vec<&str> = name.split('.').collect();
+    segments_match(&pattern, &name)
+}
+
+/**
+ * Every task matching the pattern, in the order given
+ */
+pub fn match_tasks<'a>(pattern: &str, tasks: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    tasks
+        .into_iter()
+        .filter(|task| matches_task_pattern(pattern, task))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_a_segment_at_a_time() {
+        let tasks = [
+            "format",
+            "format.rust.fix",
+            "format.rust.fmt",
+            "format.nix",
+            "install-osx.brew",
+            "install-linux.apt",
+        ];
+        assert_eq!(match_tasks("format.*", tasks), vec!["format.nix"]);
+        assert_eq!(
+            match_tasks("format.**", tasks),
+            vec!["format.rust.fix", "format.rust.fmt", "format.nix"]
+        );
+        assert_eq!(
+            match_tasks("**.f*", tasks),
+            vec!["format.rust.fix", "format.rust.fmt"]
+        );
+        assert_eq!(
+            match_tasks("install-*.*", tasks),
+            vec!["install-osx.brew", "install-linux.apt"]
+        );
+        assert!(match_tasks("lint.*", tasks).is_empty());
+        assert!(!is_task_pattern("format.rust"));
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::{self, Write};
+
+use crate::{Command, RunContext, Script, ScriptParser};
+
+const INDENT: &str = "  ";
+
+fn write_indent(out: &mut impl Write, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        out.write_str(INDENT)?;
+    }
+    Ok(())
+}
+
+fn write_script<CommandGeneric: Command>(
+    out: &mut impl Write,
+    script: &Script<CommandGeneric>,
+    parser: &impl ScriptParser<CommandGeneric>,
+    context: &RunContext,
+    depth: usize,
+) -> fmt::Result {
+    write_indent(out, depth)?;
+    match script {
+        Script::Command(command) => {
+            let command_str = command.to_string();
+            let mut lines = command_str.trim_end().lines();
+            writeln!(out, "$ {}", lines.next().unwrap_or(""))?;
+            for line in lines {
+                write_indent(out, depth + 1)?;
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+        Script::Group(group) => {
+            writeln!(out, "{}", group.kind())?;
+            for member in group.group().iter() {
+                write_script(out, member, parser, context, depth + 1)?;
+            }
+            Ok(())
+        }
+        Script::Alias(alias) => {
+            write!(out, "task {}", alias.task)?;
+            for arg in alias.args.iter() {
+                write!(out, " {}", arg)?;
+            }
+            let context = match context.enter_alias(&alias.task) {
+                Ok(context) => context,
+                Err(diagnostic) => return writeln!(out, " ({})", diagnostic.message),
+            };
+            match parser.parse(alias.task.as_str()) {
+                Ok(target) => {
+                    writeln!(out)?;
+                    write_script(out, &target, parser, &context, depth + 1)
+                }
+                Err(diagnostic) => writeln!(out, " ({})", diagnostic.message),
+            }
+        }
+        Script::Conditional(conditional) => {
+            write!(out, "if {}", conditional.condition)?;
+            if conditional.condition.planned(context) == Some(false) {
+                write!(out, " (skipped on {})", context.os())?;
+            }
+            writeln!(out)?;
+            write_script(out, &conditional.script, parser, context, depth + 1)
+        }
+        Script::Register(register) => {
+            let registered: Vec<String> =
+                [("stdout", &register.stdout), ("status", &register.status)]
+                    .into_iter()
+                    .filter_map(|(what, name)| Some(format!("{} as ${{{}}}", what, name.as_ref()?)))
+                    .collect();
+            writeln!(out, "register {}", registered.join(" and "))?;
+            write_script(out, &register.script, parser, context, depth + 1)
+        }
+    }
+}
+
+/**
+ * Renders what a script would do without running anything.
+ * Aliases are expanded inline so the output shows every command that would be executed.
+ */
+pub fn format_plan<CommandGeneric: Command>(
+    script: &Script<CommandGeneric>,
+    parser: &impl ScriptParser<CommandGeneric>,
+    context: &RunContext,
+) -> String {
+    let mut out = String::new();
+    write_script(&mut out, script, parser, context, 0).expect("Writing to a String can't fail");
+    out
+}
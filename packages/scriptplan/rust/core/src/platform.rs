@@ -0,0 +1,49 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/**
+ * The platforms that scripts can provide variants for
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    Linux,
+    MacOS,
+    Windows,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 3] = [Platform::Linux, Platform::MacOS, Platform::Windows];
+
+    /**
+     * None if scriptplan is running on something other than the platforms we have variants for
+     */
+    pub fn current() -> Option<Platform> {
+        env::consts::OS.parse().ok()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Linux => "linux",
+            Platform::MacOS => "macos",
+            Platform::Windows => "windows",
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Platform::ALL
+            .into_iter()
+            .find(|platform| platform.as_str() == name)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -0,0 +1,308 @@
+use std::io;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Child;
+
+use crate::RunContext;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/**
+ * Something that happened during a run. Tasks are named tasks (the one being run and anything aliased),
+ * commands are the individual scripts within them.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunEvent {
+    /// The task has been parsed and is about to run. The plan is what --dry-run would have printed.
+    PlanResolved {
+        task: String,
+        plan: String,
+    },
+    TaskStarted {
+        task: String,
+    },
+    /// The status is None if the task couldn't be run at all or was killed by a signal
+    TaskFinished {
+        task: String,
+        status: Option<i32>,
+        duration: Duration,
+    },
+    /// Ids are shared with commands so they're unique across both
+    GroupStarted {
+        id: usize,
+        task: String,
+        kind: &'static str,
+    },
+    GroupFinished {
+        id: usize,
+        status: Option<i32>,
+        duration: Duration,
+    },
+    CommandStarted {
+        id: usize,
+        task: String,
+        command: String,
+    },
+    /// Only sent when the reporter captures output. Output from condition probes has no command id.
+    Output {
+        id: Option<usize>,
+        stream: OutputStream,
+        chunk: String,
+    },
+    CommandFinished {
+        id: usize,
+        status: Option<i32>,
+        duration: Duration,
+    },
+    Skipped {
+        task: String,
+        condition: String,
+    },
+}
+
+/**
+ * Receives events as a plan runs. Parallel scripts report from different threads at the same time.
+ * The context is the one the event happened in, which says things like which lane it was in.
+ */
+pub trait Reporter: Send + Sync {
+    fn report(&self, context: &RunContext, event: RunEvent);
+
+    /**
+     * Whether commands should pipe their output into Output events rather than straight to the terminal
+     */
+    fn captures_output(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * What gets used when nothing else is asked for. Output normally goes straight to the terminal,
+ * so it's only echoed here when another reporter needs it captured.
+ */
+#[derive(Debug, Default)]
+pub struct TerminalReporter;
+
+impl Reporter for TerminalReporter {
+    fn report(&self, _context: &RunContext, event: RunEvent) {
+        match event {
+            // Kept out of stdout so that it doesn't end up in the task's output
+            RunEvent::Skipped { condition, .. } => eprintln!("Skipped: {}", condition),
+            RunEvent::Output {
+                stream: OutputStream::Stdout,
+                chunk,
+                ..
+            } => print!("{}", chunk),
+            RunEvent::Output {
+                stream: OutputStream::Stderr,
+                chunk,
+                ..
+            } => eprint!("{}", chunk),
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Sends every event to each of the reporters, in order
+ */
+pub struct MultiReporter(pub Vec<Arc<dyn Reporter>>);
+
+impl Reporter for MultiReporter {
+    fn report(&self, context: &RunContext, event: RunEvent) {
+        for reporter in self.0.iter() {
+            reporter.report(context, event.clone());
+        }
+    }
+
+    fn captures_output(&self) -> bool {
+        self.0.iter().any(|reporter| reporter.captures_output())
+    }
+}
+
+pub fn status_code(result: &Result<ExitStatus, ()>) -> Option<i32> {
+    result.as_ref().ok().and_then(ExitStatus::code)
+}
+
+async fn forward_output(
+    mut reader: impl AsyncRead + Unpin,
+    stream: OutputStream,
+    context: &RunContext,
+) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        context.report(RunEvent::Output {
+            id: context.command_id,
+            stream,
+            chunk: String::from_utf8_lossy(&buffer[..read]).into_owned(),
+        });
+    }
+}
+
+impl RunContext {
+    /**
+     * What a command should use for its stderr, and its stdout when it isn't part of a pipe
+     */
+    pub fn output_stdio(&self) -> Stdio {
+        if self.reporter.captures_output() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        }
+    }
+
+    /**
+     * Waits for a process spawned with output_stdio, reporting anything it prints along the way
+     */
+    pub async fn wait_for(&self, mut child: Child) -> io::Result<ExitStatus> {
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let forward_stdout = async {
+            match stdout {
+                Some(stdout) => forward_output(stdout, OutputStream::Stdout, self).await,
+                None => Ok(()),
+            }
+        };
+        let forward_stderr = async {
+            match stderr {
+                Some(stderr) => forward_output(stderr, OutputStream::Stderr, self).await,
+                None => Ok(()),
+            }
+        };
+        let (stdout_result, stderr_result) = futures::join!(forward_stdout, forward_stderr);
+        stdout_result?;
+        stderr_result?;
+        child.wait().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alias, series, Command, Condition, Plan, Script, VarArgs};
+    use async_trait::async_trait;
+    use std::collections::VecDeque;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    /**
+     * Says whether it passed on stdout and failed on stderr, so that each stream's output has a set order
+     */
+    #[derive(Debug)]
+    struct ExitCommand(i32);
+
+    impl fmt::Display for ExitCommand {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "exit {}", self.0)
+        }
+    }
+
+    #[async_trait]
+    impl Command for ExitCommand {
+        async fn run(&self, context: &RunContext, _args: VarArgs) -> Result<ExitStatus, ()> {
+            let process = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(match self.0 {
+                    0 => "echo passed".to_string(),
+                    code => format!("echo failed >&2; exit {}", code),
+                })
+                .stdout(context.stdout_stdio().map_err(|_| ())?)
+                .stderr(context.output_stdio())
+                .spawn()
+                .map_err(|_| ())?;
+            context.wait_for(process).await.map_err(|_| ())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordReporter(Mutex<Vec<String>>);
+
+    impl Reporter for RecordReporter {
+        fn report(&self, context: &RunContext, event: RunEvent) {
+            let line = match event {
+                RunEvent::PlanResolved { task, .. } => format!("plan {}", task),
+                RunEvent::TaskStarted { task } => format!("start {}", task),
+                RunEvent::TaskFinished { task, status, .. } => {
+                    format!("finish {} {:?}", task, status)
+                }
+                RunEvent::GroupStarted { id, kind, .. } => format!("{} {}", kind, id),
+                RunEvent::GroupFinished { id, status, .. } => format!("end {} {:?}", id, status),
+                RunEvent::CommandStarted { id, command, .. } => {
+                    format!("run {} {} in lane {}", id, command, context.lane)
+                }
+                RunEvent::Output { id, stream, chunk } => {
+                    format!("{:?} {:?} {}", stream, id, chunk.trim())
+                }
+                RunEvent::CommandFinished { id, status, .. } => {
+                    format!("done {} {:?}", id, status)
+                }
+                RunEvent::Skipped { condition, .. } => format!("skip {}", condition),
+            };
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn captures_output(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn reports_each_step() {
+        let plan = Plan::new().task("broken", ExitCommand(1)).task(
+            "build",
+            series![
+                ExitCommand(0),
+                alias("broken"),
+                Script::from(ExitCommand(0)).only_if(Condition::EnvSet("NOT_SET_ANYWHERE".into())),
+            ],
+        );
+        let reporter = Arc::new(RecordReporter::default());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(plan.run_with_reporter("build", VecDeque::new(), reporter.clone()))
+            .unwrap();
+
+        assert_eq!(
+            *reporter.0.lock().unwrap(),
+            vec![
+                "plan build",
+                "start build",
+                "series 0",
+                "run 1 exit 0 in lane 0",
+                "Stdout Some(1) passed",
+                "done 1 Some(0)",
+                "start broken",
+                "run 2 exit 1 in lane 0",
+                "Stderr Some(2) failed",
+                "done 2 Some(1)",
+                "finish broken Some(1)",
+                "skip $NOT_SET_ANYWHERE is set",
+                "end 0 Some(1)",
+                "finish build Some(1)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+
+use crate::RunContext;
+
+/**
+ * Where a command's stdin comes from
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stdin {
+    /// Reads from the terminal, which is what lets prompts and things like `docker run -it` work
+    Inherit,
+    Null,
+    File(PathBuf),
+    /// The output of the previous member of a pipe group
+    Pipe,
+}
+
+impl fmt::Display for Stdin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stdin::Inherit => write!(f, "inherit"),
+            Stdin::Null => write!(f, "null"),
+            Stdin::File(path) => write!(f, "{}", path.display()),
+            Stdin::Pipe => write!(f, "pipe"),
+        }
+    }
+}
+
+/**
+ * Held for as long as a command runs. Dropping it lets whatever's waiting on the terminal go.
+ */
+#[derive(Debug)]
+pub enum TerminalLock {
+    /// Nothing else can be running at the same time
+    Unshared,
+    /// The group the command is part of already holds it
+    Held,
+    Shared(OwnedRwLockReadGuard<()>),
+    Exclusive(OwnedRwLockWriteGuard<()>),
+}
+
+impl RunContext {
+    /**
+     * For everything in a group that takes one lock on the terminal for all of its members
+     */
+    pub fn holding_terminal(&self) -> RunContext {
+        let mut context = self.clone();
+        context.holds_terminal = true;
+        context
+    }
+
+    /**
+     * What stdin a command gets when its task doesn't say. Branches of a parallel group would fight over the terminal
+     * so they only get it when they ask for it.
+     */
+    pub fn default_stdin(&self) -> Stdin {
+        if self.stdin_pipe.is_some() {
+            Stdin::Pipe
+        } else if self.in_parallel {
+            Stdin::Null
+        } else {
+            Stdin::Inherit
+        }
+    }
+
+    /**
+     * Asking for a pipe outside of a pipe group gets nothing
+     */
+    pub fn stdin_stdio(&self, stdin: &Stdin) -> io::Result<Stdio> {
+        match stdin {
+            Stdin::Inherit => Ok(Stdio::inherit()),
+            Stdin::Null => Ok(Stdio::null()),
+            Stdin::File(path) => Ok(Stdio::from(File::open(path)?)),
+            Stdin::Pipe => match &self.stdin_pipe {
+                Some(pipe) => Ok(Stdio::from(pipe.try_clone()?)),
+                None => Ok(Stdio::null()),
+            },
+        }
+    }
+
+    /**
+     * What a command should use for its stdout. Output that isn't going into a pipe is handled like stderr.
+     */
+    pub fn stdout_stdio(&self) -> io::Result<Stdio> {
+        if self.discard_stdout {
+            return Ok(Stdio::null());
+        }
+        match &self.stdout_pipe {
+            Some(pipe) => Ok(Stdio::from(pipe.try_clone()?)),
+            None => Ok(self.output_stdio()),
+        }
+    }
+
+    /**
+     * Commands in a parallel group that read from the terminal wait for everything else to finish
+     * and hold off anything new until they're done. Everything else can share it.
+     */
+    pub async fn lock_terminal(&self, stdin: &Stdin) -> TerminalLock {
+        if !self.in_parallel {
+            return TerminalLock::Unshared;
+        }
+        if self.holds_terminal {
+            return TerminalLock::Held;
+        }
+        match stdin {
+            Stdin::Inherit => TerminalLock::Exclusive(self.terminal.clone().write_owned().await),
+            _ => TerminalLock::Shared(self.terminal.clone().read_owned().await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn interactive_branches_get_the_terminal_to_themselves() {
+        futures::executor::block_on(async {
+            let group = RunContext::new("build").in_parallel();
+            let branch = group.in_new_lane();
+            assert_eq!(RunContext::new("build").default_stdin(), Stdin::Inherit);
+            assert_eq!(branch.default_stdin(), Stdin::Null);
+
+            let shared = group.lock_terminal(&Stdin::Null).await;
+            assert!(branch.lock_terminal(&Stdin::Null).now_or_never().is_some());
+            assert!(branch
+                .lock_terminal(&Stdin::Inherit)
+                .now_or_never()
+                .is_none());
+            drop(shared);
+
+            let exclusive = branch.lock_terminal(&Stdin::Inherit).await;
+            assert!(group.lock_terminal(&Stdin::Null).now_or_never().is_none());
+            // Members of a group that already holds it don't wait behind the interactive branch
+            assert!(matches!(
+                group
+                    .holding_terminal()
+                    .lock_terminal(&Stdin::Null)
+                    .now_or_never(),
+                Some(TerminalLock::Held)
+            ));
+            drop(exclusive);
+            assert!(group.lock_terminal(&Stdin::Null).now_or_never().is_some());
+        });
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use scriptplan_lang_utils::has_parameters;
+
+use crate::{Alias, Command, Diagnostic, NodePath, Script, ScriptParser};
+
+/**
+ * None means the number of arguments depends on what the user passes in
+ */
+type ArgCount = Option<usize>;
+
+/**
+ * The positional arguments (like "$0") an alias references, as indexes
+ */
+fn alias_positionals(alias: &Alias) -> impl Iterator<Item = usize> + '_ {
+    alias.args.iter().filter_map(|arg| {
+        let index = arg.strip_prefix('$')?;
+        if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) {
+            index.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+enum Visit {
+    OnStack,
+    Finished,
+}
+
+struct Validator<'p, CommandGeneric: Command, Parser: ScriptParser<CommandGeneric>> {
+    parser: &'p Parser,
+    task_names: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    seen_diagnostics: HashSet<Diagnostic>,
+    visited: HashSet<(String, ArgCount)>,
+    /// Which tasks each task directly aliases, along with the first place it does
+    aliases: BTreeMap<String, Vec<(String, NodePath)>>,
+    _command: std::marker::PhantomData<CommandGeneric>,
+}
+
+impl<CommandGeneric: Command, Parser: ScriptParser<CommandGeneric>>
+    Validator<'_, CommandGeneric, Parser>
+{
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.seen_diagnostics.insert(diagnostic.clone()) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn check_task(&mut self, task: &str, arg_count: ArgCount, chain: &mut Vec<String>) {
+        if !self.visited.insert((task.to_string(), arg_count)) {
+            return;
+        }
+        match self.parser.parse(task) {
+            Ok(script) => {
+                chain.push(task.to_string());
+                self.check_script(&script, &NodePath::task(task), arg_count, chain);
+                chain.pop();
+            }
+            Err(diagnostic) => self.report(diagnostic),
+        }
+    }
+
+    fn check_script(
+        &mut self,
+        script: &Script<CommandGeneric>,
+        path: &NodePath,
+        arg_count: ArgCount,
+        chain: &mut Vec<String>,
+    ) {
+        match script {
+            Script::Command(command) => {
+                if let (Some(count), Some(required)) = (arg_count, command.required_args()) {
+                    if required > count {
+                        self.report(Diagnostic::new(
+                            path.clone(),
+                            format!(
+                                "References argument {} but only {} can ever be passed to it when run through {}",
+                                required,
+                                count,
+                                chain.join(" -> ")
+                            ),
+                        ));
+                    }
+                }
+            }
+            Script::Group(group) => {
+                let key = group.kind();
+                for (i, member) in group.group().iter().enumerate() {
+                    let member_count = match member.as_ref() {
+                        // See member_args
+                        Script::Alias(alias) if !has_parameters(&alias.args) => Some(0),
+                        _ => arg_count,
+                    };
+                    self.check_script(member, &path.key(key).index(i), member_count, chain);
+                }
+            }
+            Script::Alias(alias) => self.check_alias(alias, &path.key("task"), arg_count, chain),
+            Script::Conditional(conditional) => {
+                self.check_script(&conditional.script, path, arg_count, chain)
+            }
+            Script::Register(register) => {
+                self.check_script(&register.script, path, arg_count, chain)
+            }
+        }
+    }
+
+    fn check_alias(
+        &mut self,
+        alias: &Alias,
+        path: &NodePath,
+        arg_count: ArgCount,
+        chain: &mut Vec<String>,
+    ) {
+        if let Some(task_name) = path.task_name() {
+            // Tasks get checked once per argument count so the same alias can turn up more than once
+            let edges = self.aliases.entry(task_name.to_string()).or_default();
+            if !edges.iter().any(|(target, _)| *target == alias.task) {
+                edges.push((alias.task.clone(), path.clone()));
+            }
+        }
+
+        if !self.task_names.contains(&alias.task) {
+            self.report(Diagnostic::missing_task(
+                path.clone(),
+                &alias.task,
+                &self.task_names,
+            ));
+            return;
+        }
+
+        // Mirrors how Alias::run works out what to pass on
+        let target_count = if has_parameters(&alias.args) {
+            if let Some(count) = arg_count {
+                let missing: Vec<_> = alias_positionals(alias)
+                    .filter(|index| *index >= count)
+                    .collect();
+                for index in missing {
+                    self.report(Diagnostic::new(
+                        path.clone(),
+                        format!(
+                            "\"${}\" will never be provided since only {} argument(s) can be passed in when run through {}",
+                            index,
+                            count,
+                            chain.join(" -> ")
+                        ),
+                    ));
+                }
+            }
+            Some(alias.args.len())
+        } else {
+            arg_count.map(|count| count + alias.args.len())
+        };
+
+        if !chain.contains(&alias.task) {
+            self.check_task(&alias.task, target_count, chain);
+        }
+    }
+
+    fn check_cycles(&mut self) {
+        let mut visits = HashMap::new();
+        for start in self.aliases.keys().cloned().collect::<Vec<_>>() {
+            if !visits.contains_key(&start) {
+                self.find_cycles(start, &mut Vec::new(), &mut visits);
+            }
+        }
+    }
+
+    /**
+     * Tasks that haven't been visited yet aren't in visits. Following an alias back to a task that's still on the
+     * stack closes a cycle, and finished tasks never get searched again so each alias is only followed once.
+     */
+    fn find_cycles(
+        &mut self,
+        task: String,
+        stack: &mut Vec<String>,
+        visits: &mut HashMap<String, Visit>,
+    ) {
+        visits.insert(task.clone(), Visit::OnStack);
+        stack.push(task.clone());
+        let edges = self.aliases.get(&task).cloned().unwrap_or_default();
+        for (target, path) in edges {
+            match visits.get(&target) {
+                Some(Visit::OnStack) => {
+                    let position = stack.iter().position(|task| *task == target).unwrap();
+                    // Point at the alias that closes the loop back to the start of the cycle
+                    self.report(Diagnostic::alias_cycle(path, &stack[position..], &target));
+                }
+                Some(Visit::Finished) => {}
+                None => self.find_cycles(target, stack, visits),
+            }
+        }
+        stack.pop();
+        visits.insert(task, Visit::Finished);
+    }
+}
+
+/**
+ * Eagerly parses every task and looks for problems that would otherwise only show up part way through a run
+ */
+pub fn validate<CommandGeneric: Command>(
+    parser: &impl ScriptParser<CommandGeneric>,
+) -> Vec<Diagnostic> {
+    let mut task_names = parser.task_names();
+    task_names.sort();
+
+    let mut validator = Validator {
+        parser,
+        task_names: task_names.clone(),
+        diagnostics: Vec::new(),
+        seen_diagnostics: HashSet::new(),
+        visited: HashSet::new(),
+        aliases: BTreeMap::new(),
+        _command: std::marker::PhantomData,
+    };
+
+    for task in task_names.iter() {
+        validator.check_task(task, None, &mut Vec::new());
+    }
+    validator.check_cycles();
+
+    validator.diagnostics
+}
+
+fn script_required_args<CommandGeneric: Command>(
+    script: &Script<CommandGeneric>,
+    parser: &impl ScriptParser<CommandGeneric>,
+    chain: &mut Vec<String>,
+) -> usize {
+    match script {
+        Script::Command(command) => command.required_args().unwrap_or(0),
+        Script::Group(group) => group
+            .group()
+            .iter()
+            .map(|member| match member.as_ref() {
+                // See member_args
+                Script::Alias(alias) if !has_parameters(&alias.args) => 0,
+                member => script_required_args(member, parser, chain),
+            })
+            .max()
+            .unwrap_or(0),
+        Script::Alias(alias) if has_parameters(&alias.args) => alias_positionals(alias)
+            .map(|index| index + 1)
+            .max()
+            .unwrap_or(0),
+        Script::Alias(alias) => {
+            if chain.contains(&alias.task) {
+                return 0;
+            }
+            let required = task_required_args(&alias.task, parser, chain);
+            // The alias's own arguments come first
+            required.saturating_sub(alias.args.len())
+        }
+        Script::Conditional(conditional) => {
+            script_required_args(&conditional.script, parser, chain)
+        }
+        Script::Register(register) => script_required_args(&register.script, parser, chain),
+    }
+}
+
+fn task_required_args<CommandGeneric: Command>(
+    task: &str,
+    parser: &impl ScriptParser<CommandGeneric>,
+    chain: &mut Vec<String>,
+) -> usize {
+    let script = match parser.parse(task) {
+        Ok(script) => script,
+        Err(_) => return 0,
+    };
+    chain.push(task.to_string());
+    let required = script_required_args(&script, parser, chain);
+    chain.pop();
+    required
+}
+
+/**
+ * How many positional arguments a task references, as far as its backends are able to tell
+ */
+pub fn required_args<CommandGeneric: Command>(
+    task: &str,
+    parser: &impl ScriptParser<CommandGeneric>,
+) -> usize {
+    task_required_args(task, parser, &mut Vec::new())
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+use crate::{Command, DynCommand, RunContext};
+
+#[derive(Debug)]
+enum VarDefinition {
+    Literal(String),
+    /// Takes the command's trimmed stdout
+    Command(DynCommand),
+}
+
+/**
+ * A variable that gets worked out the first time something uses it and is then reused for the rest of the run
+ */
+#[derive(Debug)]
+pub struct LazyVar {
+    definition: VarDefinition,
+    value: OnceCell<String>,
+}
+
+impl LazyVar {
+    pub fn literal(value: impl Into<String>) -> Self {
+        LazyVar {
+            definition: VarDefinition::Literal(value.into()),
+            value: OnceCell::new(),
+        }
+    }
+
+    pub fn command(command: DynCommand) -> Self {
+        LazyVar {
+            definition: VarDefinition::Command(command),
+            value: OnceCell::new(),
+        }
+    }
+
+    async fn value(&self, name: &str, context: &RunContext) -> Result<String, ()> {
+        let command = match &self.definition {
+            VarDefinition::Literal(value) => return Ok(value.clone()),
+            VarDefinition::Command(command) => command,
+        };
+        let value = self
+            .value
+            .get_or_try_init(|| async {
+                // Without this, a var that used itself would wait on itself forever
+                let mut context = context.clone();
+                context.defined = Arc::default();
+                context.stdin_pipe = None;
+                let (stdout, result) = context
+                    .capture_stdout(|context| async move {
+                        command.run(&context, VecDeque::new()).await
+                    })
+                    .await?;
+                match result {
+                    Ok(status) if status.success() => Ok(stdout.trim().to_string()),
+                    _ => {
+                        eprintln!(
+                            "Unable to work out ${{{}}} because \"{}\" failed",
+                            name, command
+                        );
+                        Err(())
+                    }
+                }
+            })
+            .await?;
+        Ok(value.clone())
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/**
+ * Whether the text uses the variable as $name, ${name} or Nu's $env.name
+ */
+fn refers_to(text: &str, name: &str) -> bool {
+    ["$", "${", "$env."].into_iter().any(|prefix| {
+        let reference = format!("{}{}", prefix, name);
+        text.match_indices(&reference)
+            .any(|(i, _)| !text[i + reference.len()..].starts_with(is_name_char))
+    })
+}
+
+impl RunContext {
+    pub fn with_vars(mut self, vars: Arc<BTreeMap<String, LazyVar>>) -> Self {
+        self.defined = vars;
+        self
+    }
+
+    /**
+     * Starts with the current registered variables, but anything registered afterwards doesn't make it back out
+     */
+    pub fn in_new_scope(&self) -> RunContext {
+        let mut context = self.clone();
+        let registered = self.registered.lock().unwrap().clone();
+        context.registered = Arc::new(Mutex::new(registered));
+        context
+    }
+
+    pub fn register(&self, name: &str, value: impl Into<String>) {
+        self.registered
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value.into());
+    }
+
+    /**
+     * Every registered variable and literal defined one, plus the defined commands that the text uses. Those get
+     * worked out if they haven't been already. Literals cost nothing so interpreters that read the environment their
+     * own way (like os.environ["name"]) still get them.
+     */
+    pub async fn vars_in(&self, text: &str) -> Result<BTreeMap<String, String>, ()> {
+        let mut vars = BTreeMap::new();
+        for (name, var) in self.defined.iter() {
+            if matches!(var.definition, VarDefinition::Literal(_)) || refers_to(text, name) {
+                vars.insert(name.clone(), var.value(name, self).await?);
+            }
+        }
+        vars.extend(self.registered.lock().unwrap().clone());
+        Ok(vars)
+    }
+
+    /**
+     * Replaces ${name} with the variable's value. Anything else that looks like a variable is left alone.
+     */
+    pub async fn substitute_vars(&self, text: &str) -> Result<String, ()> {
+        let vars = self.vars_in(text).await?;
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            let name_and_rest = &rest[start + 2..];
+            let value = name_and_rest
+                .find('}')
+                .and_then(|end| Some((end, vars.get(&name_and_rest[..end])?)));
+            match value {
+                Some((end, value)) => {
+                    out.push_str(&rest[..start]);
+                    out.push_str(value);
+                    rest = &name_and_rest[end + 1..];
+                }
+                None => {
+                    out.push_str(&rest[..start + 2]);
+                    rest = name_and_rest;
+                }
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /**
+     * Runs something with its stdout going into a pipe that's read until everything writing to it has finished
+     */
+    pub async fn capture_stdout<T, Run, Running>(&self, run: Run) -> Result<(String, T), ()>
+    where
+        Run: FnOnce(RunContext) -> Running,
+        Running: Future<Output = T>,
+    {
+        let (mut reader, writer) = std::io::pipe().map_err(|err| {
+            eprintln!("Unable to create a pipe: {}", err);
+        })?;
+        let reading = tokio::task::spawn_blocking(move || {
+            let mut stdout = String::new();
+            reader.read_to_string(&mut stdout).map(|_| stdout)
+        });
+
+        let mut context = self.clone();
+        context.stdout_pipe = Some(Arc::new(writer));
+        // The context gets dropped once it's finished running, which closes the pipe
+        let result = run(context).await;
+
+        match reading.await {
+            Ok(Ok(stdout)) => Ok((stdout, result)),
+            _ => {
+                eprintln!("Unable to read the output of {}", self.task());
+                Err(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::fmt;
+    use std::process::ExitStatus;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct CountCommand(Arc<AtomicUsize>);
+
+    impl fmt::Display for CountCommand {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "count")
+        }
+    }
+
+    #[async_trait]
+    impl Command for CountCommand {
+        async fn run(&self, context: &RunContext, _args: crate::VarArgs) -> Result<ExitStatus, ()> {
+            let count = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut process = tokio::process::Command::new("echo")
+                .arg(format!(" {} ", count))
+                .stdout(context.stdout_stdio().unwrap())
+                .spawn()
+                .map_err(|_| ())?;
+            process.wait().await.map_err(|_| ())
+        }
+    }
+
+    #[test]
+    fn scopes_registered_vars() {
+        futures::executor::block_on(async {
+            let build = RunContext::new("build");
+            build.register("version", "1.2");
+            let series = build.in_new_scope();
+            series.register("path", "out/app");
+            assert_eq!(
+                series
+                    .substitute_vars("cp ${path} ${path}-${version} ${unknown} ${")
+                    .await,
+                Ok("cp out/app out/app-1.2 ${unknown} ${".to_string())
+            );
+            assert_eq!(
+                build.substitute_vars("${path}").await,
+                Ok("${path}".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn works_out_defined_vars_once_when_used() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let vars = BTreeMap::from([
+            (
+                "sha".to_string(),
+                LazyVar::command(Box::new(CountCommand(runs.clone()))),
+            ),
+            ("name".to_string(), LazyVar::literal("app")),
+        ]);
+        let context = RunContext::new("build").with_vars(Arc::new(vars));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            assert_eq!(
+                context.vars_in("echo $name").await,
+                Ok(BTreeMap::from([("name".to_string(), "app".to_string())]))
+            );
+            assert_eq!(
+                context.vars_in("print(os.environ['name'])").await,
+                Ok(BTreeMap::from([("name".to_string(), "app".to_string())]))
+            );
+            assert_eq!(runs.load(Ordering::Relaxed), 0);
+            // $shasum isn't $sha, and neither is the word on its own
+            for text in [
+                "echo $shasum",
+                "echo ${shasum}",
+                "git rev-parse HEAD # sha",
+                "print(os.environ['sha'])",
+            ] {
+                assert!(!context.vars_in(text).await.unwrap().contains_key("sha"));
+            }
+            assert_eq!(runs.load(Ordering::Relaxed), 0);
+            for text in ["echo $sha", "echo ${sha:-none}", "print $env.sha"] {
+                assert_eq!(
+                    context.vars_in(text).await.unwrap().get("sha"),
+                    Some(&"1".to_string())
+                );
+            }
+
+            assert_eq!(
+                context.substitute_vars("${name}-${sha}").await,
+                Ok("app-1".to_string())
+            );
+            assert_eq!(context.substitute_vars("${sha}").await, Ok("1".to_string()));
+            assert_eq!(runs.load(Ordering::Relaxed), 1);
+        });
+    }
+}
@@ -1,3 +1,127 @@
-fn main() {
-    print!("TODO")
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::ExitStatus;
+
+use async_trait::async_trait;
+
+use scriptplan_core::{Command, RunContext, Stdin, VarArgs};
+
+pub extern crate scriptplan_core;
+
+#[derive(Debug)]
+pub struct NuCommand {
+    pub source: String,
+    /// Falls back to the context's default when not set
+    pub stdin: Option<Stdin>,
+    /// Set on top of the vars the script uses. Values can use ${var}.
+    pub env: BTreeMap<String, String>,
+    /// Runs in the current directory when not set. Can use ${var}.
+    pub cwd: Option<String>,
+}
+
+impl From<&str> for NuCommand {
+    fn from(source: &str) -> Self {
+        NuCommand {
+            source: source.to_string(),
+            stdin: None,
+            env: BTreeMap::new(),
+            cwd: None,
+        }
+    }
+}
+
+impl fmt::Display for NuCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(nu) {}", self.source)
+    }
+}
+
+/**
+ * Nu has no equivalent of bash's "$@" so, like bash, arguments are spread onto the end of the script unless it references variables itself
+ */
+fn with_spread_args(source: &str, args: &VarArgs) -> String {
+    if source.contains('$') {
+        return source.to_string();
+    }
+    args.iter().fold(source.to_string(), |script, arg| {
+        format!("{} {}", script, raw_string(arg))
+    })
+}
+
+/**
+ * Nothing inside a raw string (r#'...'#) gets interpreted, as long as it uses more #s than follow any quote inside it
+ */
+fn raw_string(value: &str) -> String {
+    let mut hashes = "#".to_string();
+    while value.contains(&format!("'{}", hashes)) {
+        hashes.push('#');
+    }
+    format!("r{0}'{1}'{0}", hashes, value)
+}
+
+#[async_trait]
+impl Command for NuCommand {
+    async fn run(&self, context: &RunContext, args: VarArgs) -> Result<ExitStatus, ()> {
+        let stdin = self
+            .stdin
+            .clone()
+            .unwrap_or_else(|| context.default_stdin());
+        let stdin_stdio = context.stdin_stdio(&stdin).map_err(|err| {
+            eprintln!("Unable to open \"{}\" for stdin: {}", stdin, err);
+        })?;
+
+        // Scripts read vars through $env rather than having them pasted in
+        let vars = context.vars_in(&self.source).await?;
+        let mut env = BTreeMap::new();
+        for (name, value) in &self.env {
+            env.insert(name.clone(), context.substitute_vars(value).await?);
+        }
+        let cwd = match &self.cwd {
+            Some(cwd) => Some(context.substitute_vars(cwd).await?),
+            None => None,
+        };
+        let stdout_stdio = context.stdout_stdio().map_err(|err| {
+            eprintln!("Unable to connect stdout to the pipe: {}", err);
+        })?;
+
+        let _terminal = context.lock_terminal(&stdin).await;
+        let mut process = tokio::process::Command::new("nu");
+        if let Some(cwd) = &cwd {
+            process.current_dir(cwd);
+        }
+        let process = process
+            .stdin(stdin_stdio)
+            .stdout(stdout_stdio)
+            .stderr(context.output_stdio())
+            .envs(vars)
+            .envs(env)
+            .arg("-c")
+            .arg(with_spread_args(&self.source, &args))
+            .spawn()
+            .map_err(|err| {
+                eprintln!("Unable to start \"nu\": {}", err);
+            })?;
+        context.wait_for(process).await.map_err(|err| {
+            eprintln!("Unable to wait for \"nu\": {}", err);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn spreads_args_as_raw_strings() {
+        let args: VarArgs = ["plain", "it's \"quoted\" $HOME\\n", "ends '#"]
+            .into_iter()
+            .map(|arg| Arc::new(arg.to_string()))
+            .collect();
+        assert_eq!(
+            with_spread_args("ls", &args),
+            "ls r#'plain'# r#'it's \"quoted\" $HOME\\n'# r##'ends '#'##"
+        );
+        assert_eq!(with_spread_args("ls $env.PWD", &args), "ls $env.PWD");
+    }
 }